@@ -0,0 +1,158 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand, ValueEnum};
+use ethers_core::types::Address;
+use ethers_providers::{JsonRpcClient, Provider};
+
+use crate::{config::{glob_match, next_cut, Config}, get_block_number, metrics::ToChecksumHex as _, save_stage, tasks::{check, ContractStage, Task}, DatasetName, Stage};
+
+#[derive(Debug, Parser)]
+#[command(about = "dump on-chain metrics into parquet files")]
+pub struct Cli {
+  #[arg(short, long, default_value = "dump.toml")]
+  pub config: PathBuf,
+  /// overrides `data_dir` in config file
+  #[arg(long, env = "DATA_DIR")]
+  pub data_dir: Option<PathBuf>,
+  /// overrides `endpoint` in config file
+  #[arg(long, env = "RETH_HTTP_RPC")]
+  pub endpoint: Option<String>,
+  /// only run tasks matching the family or name pattern, replaces `tasks.include` in config file
+  #[arg(long, global = true)]
+  pub include: Vec<String>,
+  /// skip tasks matching the family or name pattern, added to `tasks.exclude` in config file
+  #[arg(long, global = true)]
+  pub exclude: Vec<String>,
+  /// serve blocks and logs only from `cache_dir`, fail on anything not cached
+  #[arg(long, global = true)]
+  pub offline: bool,
+  /// save every request and its response into the file, as a fixture to replay in tests
+  #[arg(long, global = true)]
+  pub record: Option<PathBuf>,
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+  /// Fetch every selected task up to the head
+  Run {
+    /// keep running when catched up, and fetch new blocks as they arrive
+    #[arg(long)]
+    follow: bool,
+  },
+  /// Show checkpoint of every task
+  Status,
+  /// Show ranges the next run would fetch
+  Plan,
+  /// Rewind tasks to the block, data at and after the block is removed and fetched again on next run
+  Reset {
+    /// task name or family, `*` and `?` match any characters and one character in the name
+    pattern: String,
+    block: u64,
+  },
+  /// Check data files against checkpoints of the stage
+  Check {
+    /// remove tmp and merged files, trim data past checkpoints and rewind tasks to their first gap
+    #[arg(long)]
+    repair: bool,
+  },
+  /// Register a contract to be tracked
+  AddPair {
+    kind: PairKind,
+    name: String,
+    contract: String,
+    created: u64,
+  },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum PairKind {
+  Uniswap2,
+  Uniswap3,
+  Pendle2,
+}
+
+pub async fn status<P: JsonRpcClient>(client: &Provider<P>, config: &Config, stage: &Stage) -> Result<()> {
+  let head = get_block_number(client, config, stage).await?;
+  println!("head {}", head);
+  for task in stage.tasks() {
+    let checkpoint = task.checkpoint.load(std::sync::atomic::Ordering::SeqCst);
+    let skipped = if task.is_finished() { " (finished)" } else if config.tasks.is_selected(task.family, &task.name) { "" } else { " (skipped)" };
+    println!("{}\t{}\t-{}{}", task.name, checkpoint, head.saturating_sub(checkpoint), skipped);
+  }
+  Ok(())
+}
+
+pub async fn plan<P: JsonRpcClient>(client: &Provider<P>, config: &Config, stage: &Stage) -> Result<()> {
+  let end = get_block_number(client, config, stage).await?;
+  println!("head {}", end);
+  for Task { family, name, checkpoint, finished, .. } in stage.tasks() {
+    if finished.load(std::sync::atomic::Ordering::SeqCst) || !config.tasks.is_selected(family, &name) {
+      continue;
+    }
+    let mut start = checkpoint.load(std::sync::atomic::Ordering::SeqCst);
+    while start < end {
+      // chunks as written by `RunConfig::run`
+      let checkpoint = next_cut(start, config.cut).min(end).min(start + config.batch.max(1));
+      let dataset = DatasetName::new(&name, config.cut, (start / config.cut) as usize);
+      let filename = if checkpoint == next_cut(start, config.cut) { dataset.filename() } else { dataset.part_filename(start, checkpoint) };
+      println!("{}\t{}..{}\t{}", name, start, checkpoint, filename);
+      start = checkpoint;
+    }
+  }
+  Ok(())
+}
+
+pub fn reset(config: &Config, stage: &Stage, pattern: &str, block: u64) -> Result<()> {
+  let tasks = stage.tasks().into_iter().filter(|i| i.family == pattern || glob_match(pattern, &i.name)).collect::<Vec<_>>();
+  if tasks.is_empty() {
+    anyhow::bail!("no task matches {pattern:?} in stage");
+  }
+  for task in tasks {
+    let checkpoint = task.checkpoint.load(std::sync::atomic::Ordering::SeqCst);
+    if checkpoint <= block {
+      println!("{}\t{}\tnot reached yet", task.name, checkpoint);
+      continue;
+    }
+    task.rewind(&config.data_dir, config.cut, block)?;
+    // save after each task, so the stage never claims data already removed
+    save_stage(&config.data_dir, stage)?;
+    println!("{}\t{} -> {}", task.name, checkpoint, block);
+  }
+  Ok(())
+}
+
+pub fn check(config: &Config, stage: &Stage, repair: bool) -> Result<()> {
+  let issues = check::verify(&config.data_dir, config.cut, stage)?;
+  for issue in &issues {
+    println!("{}", issue);
+  }
+  if issues.is_empty() {
+    println!("ok");
+  } else if repair {
+    check::repair(&config.data_dir, config.cut, stage, &issues)?;
+    save_stage(&config.data_dir, stage)?;
+    println!("repaired");
+  }
+  Ok(())
+}
+
+pub fn add_pair(config: &Config, stage: &mut Stage, kind: PairKind, name: String, contract: &str, created: u64) -> Result<()> {
+  let contract = contract.parse::<Address>().map_err(|e| anyhow::anyhow!("invalid contract address {contract:?}: {e}"))?;
+  let pairs = match kind {
+    PairKind::Uniswap2 => &mut stage.uniswap.uniswap_pair_events,
+    PairKind::Uniswap3 => &mut stage.uniswap.uniswap3_pair_events,
+    PairKind::Pendle2 => &mut stage.pendle.pendle2_market_events,
+  };
+  if pairs.contains_key(&name) {
+    anyhow::bail!("{kind:?} pair {name:?} already exists");
+  }
+  let pair = ContractStage::new(contract.to_checksum_hex(), created);
+  pair.init_checkpoint(config.cut);
+  info!(?kind, name, ?pair, "add pair");
+  pairs.insert(name, pair);
+  stage.validate(None)?;
+  save_stage(&config.data_dir, stage)
+}
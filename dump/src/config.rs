@@ -1,14 +1,170 @@
-use std::path::PathBuf;
+use std::{path::{Path, PathBuf}, sync::{atomic::AtomicU64, Arc}};
 
-#[derive(Debug, Clone)]
+use ethers_core::types::{Address, BlockNumber};
+use indexmap::IndexMap;
+
+/// Which block is taken as the head before `confirmations` is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadTag {
+  Latest,
+  Safe,
+  Finalized,
+}
+
+impl From<HeadTag> for BlockNumber {
+  fn from(tag: HeadTag) -> Self {
+    match tag {
+      HeadTag::Latest => BlockNumber::Latest,
+      HeadTag::Safe => BlockNumber::Safe,
+      HeadTag::Finalized => BlockNumber::Finalized,
+    }
+  }
+}
+
+/// Patterns of tasks to run, an empty `include` means all tasks.
+/// A pattern matches a task by its family (`block_metrics`, `uniswap`, `pendle`),
+/// or by its name with `*` and `?` wildcards, e.g. `uniswap_pair_events_usdc_*`.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct TaskSelect {
+  pub include: Vec<String>,
+  pub exclude: Vec<String>,
+}
+
+impl TaskSelect {
+  pub fn is_selected(&self, family: &str, name: &str) -> bool {
+    let matches = |pattern: &String| pattern == family || glob_match(pattern, name);
+    (self.include.is_empty() || self.include.iter().any(matches)) && !self.exclude.iter().any(matches)
+  }
+}
+
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+  match pattern.chars().next() {
+    None => name.is_empty(),
+    Some('*') => (0..=name.len()).filter(|&i| name.is_char_boundary(i)).any(|i| glob_match(&pattern[1..], &name[i..])),
+    Some(c) => {
+      let mut chars = name.chars();
+      match chars.next() {
+        Some(n) if c == '?' || c == n => glob_match(&pattern[c.len_utf8()..], chars.as_str()),
+        _ => false,
+      }
+    }
+  }
+}
+
+/// Initial blocks per `eth_getLogs` request, each task adjusts its own page size as it goes.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct PageSize {
+  /// factory scans match a topic over every contract
+  pub factory: u64,
+  /// pair scans match every topic of a single contract
+  pub pair: u64,
+}
+
+impl Default for PageSize {
+  fn default() -> Self {
+    Self { factory: 10000, pair: 2000 }
+  }
+}
+
+/// Tokens to auto-register pools of, from factory events.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct Registry {
+  /// symbol = address, pools are registered only if both tokens are listed,
+  /// and named by their symbols like `usdc_weth`
+  pub tokens: IndexMap<String, Address>,
+  /// symbols, if not empty, one side of the pool must be a base token
+  pub base_tokens: Vec<String>,
+  /// fee tiers of V3 pools to register, e.g. 500 for 0.05%, empty means all
+  pub uniswap3_fees: Vec<u32>,
+  pub pendle2: PendleRegistry,
+}
+
+/// Pendle markets are registered if either the underlying token or the PT name matches.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct PendleRegistry {
+  /// symbols in `tokens`
+  pub underlying: Vec<String>,
+  /// patterns with `*` and `?` wildcards, e.g. `PT-*ETH-*`
+  pub pt_names: Vec<String>,
+}
+
+impl Registry {
+  pub fn symbol(&self, address: &Address) -> Option<&str> {
+    self.tokens.iter().find(|(_, i)| *i == address).map(|(symbol, _)| symbol.as_str())
+  }
+
+  pub fn pair_name(&self, token0: &Address, token1: &Address) -> Option<String> {
+    let symbol0 = self.symbol(token0)?;
+    let symbol1 = self.symbol(token1)?;
+    if !self.base_tokens.is_empty() && !self.base_tokens.iter().any(|i| i == symbol0 || i == symbol1) {
+      return None;
+    }
+    Some(format!("{}_{}", symbol0, symbol1))
+  }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
 pub struct Config {
   pub data_dir: PathBuf,
+  /// `http://host:port`, `ws://host:port`, or a path of IPC socket like `/tmp/reth.ipc`,
+  /// the scheme defaults to http if omitted
   pub endpoint: String,
+  /// more endpoints to spread requests over, and to fall back to when one fails
+  pub endpoints: Vec<String>,
+  /// endpoints with a head more than this behind the best one are skipped
+  pub max_lag: u64,
+  /// keep raw responses of blocks and logs here, so datasets could be decoded again offline
+  pub cache_dir: Option<PathBuf>,
+  /// serve requests only from `cache_dir`, the head is where the last round ended
+  #[serde(skip)]
+  pub offline: bool,
+  #[serde(skip)]
   pub block_length: u64,
+  /// blocks below are past `confirmations`, shared with the cache which keeps only responses of them
+  #[serde(skip)]
+  pub confirmed: Arc<AtomicU64>,
   pub cut: u64,
+  /// blocks per executor call, each batch is written to disk before the next one
+  pub batch: u64,
+  /// keep polling the head and run tasks again when new blocks arrive
+  #[serde(skip)]
+  pub follow: bool,
+  /// in seconds
+  pub poll_interval: u64,
+  pub head_tag: HeadTag,
+  /// blocks behind `head_tag` that are not indexed yet
+  pub confirmations: u64,
+  pub tasks: TaskSelect,
+  pub page_size: PageSize,
+  /// concurrent requests when fetching blocks
+  pub concurrency: usize,
+  /// concurrent `eth_getLogs` pages of a task
+  pub log_concurrency: usize,
+  /// retries of a failed request, with backoff doubled each time
+  pub retries: usize,
+  /// blocks per JSON-RPC batch request, 1 to request them one by one
+  pub block_batch: usize,
+  /// tasks running at the same time
+  pub parallel_tasks: usize,
+  /// requests in flight at the same time, shared by all tasks
+  pub max_in_flight: usize,
+  /// requests in flight at the same time to each endpoint
+  pub endpoint_max_in_flight: usize,
+  /// requests per second to each endpoint, 0 means unlimited
+  pub endpoint_rps: u32,
+  pub registry: Registry,
 }
 
 const DEFAULT_CUT: u64 = 1000000;
+const DEFAULT_BATCH: u64 = 100000;
+/// about one block on mainnet
+const DEFAULT_POLL_INTERVAL: u64 = 12;
 /// A cut means 0..CUT, CUT..2*CUT, etc.
 /// which means block number 10000 is in a new file.
 /// just like what reth do.
@@ -16,22 +172,88 @@ pub const fn next_cut(i: u64, cut: u64) -> u64 {
   i / cut * cut + cut
 }
 
-impl Config {
-  pub fn default() -> Self {
+impl Default for Config {
+  fn default() -> Self {
     Self {
       data_dir: "data".to_string().into(),
       endpoint: "http://localhost:8545".to_string(),
+      endpoints: Vec::new(),
+      max_lag: 8,
+      cache_dir: None,
+      offline: false,
       block_length: 0,
+      confirmed: Default::default(),
       cut: DEFAULT_CUT,
+      batch: DEFAULT_BATCH,
+      follow: false,
+      poll_interval: DEFAULT_POLL_INTERVAL,
+      head_tag: HeadTag::Latest,
+      confirmations: 0,
+      tasks: TaskSelect::default(),
+      page_size: PageSize::default(),
+      concurrency: 500,
+      log_concurrency: 4,
+      retries: 3,
+      block_batch: 100,
+      parallel_tasks: 4,
+      max_in_flight: 500,
+      endpoint_max_in_flight: 500,
+      endpoint_rps: 0,
+      registry: Registry::default(),
     }
   }
+}
 
-  pub fn from_env() -> Self {
-    Self {
-      data_dir: std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string()).into(),
-      endpoint: format!("http://{}", std::env::var("RETH_HTTP_RPC").as_deref().unwrap_or("127.0.0.1:8545")),
-      block_length: 0,
-      cut: DEFAULT_CUT,
+impl Config {
+  /// Read config from a toml file, a missing file means all defaults.
+  pub fn load<P: AsRef<Path>>(filename: P) -> anyhow::Result<Self> {
+    let filename = filename.as_ref();
+    let mut config = match std::fs::read_to_string(filename) {
+      Ok(content) => toml::from_str::<Config>(&content)?,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        info!(config_file=%filename.display(), "config file not found, using default");
+        Config::default()
+      }
+      Err(e) => return Err(e)?,
+    };
+    config.set_endpoint(config.endpoint.clone());
+    config.endpoints = config.endpoints.into_iter().map(with_scheme).collect();
+    Ok(config)
+  }
+
+  pub fn set_endpoint(&mut self, endpoint: String) {
+    self.endpoint = with_scheme(endpoint);
+  }
+
+  /// `endpoint` followed by `endpoints`, without duplicates.
+  pub fn all_endpoints(&self) -> Vec<&str> {
+    let mut result = vec![self.endpoint.as_str()];
+    for endpoint in &self.endpoints {
+      if !result.contains(&endpoint.as_str()) {
+        result.push(endpoint);
+      }
     }
+    result
   }
 }
+
+fn with_scheme(endpoint: String) -> String {
+  if endpoint.contains("://") || is_ipc_path(&endpoint) { endpoint } else { format!("http://{}", endpoint) }
+}
+
+/// An absolute or relative path, or a file ending with `.ipc`.
+pub fn is_ipc_path(endpoint: &str) -> bool {
+  !endpoint.contains("://") && (endpoint.starts_with('/') || endpoint.starts_with('.') || endpoint.ends_with(".ipc"))
+}
+
+#[test]
+fn test_task_select() {
+  assert!(glob_match("uniswap_pair_events_usdc_*", "uniswap_pair_events_usdc_weth"));
+  assert!(glob_match("*_usdc_?eth", "uniswap3_pair_events_usdc_weth"));
+  assert!(!glob_match("uniswap_pair_events_usdc_*", "uniswap3_pair_events_usdc_weth"));
+  let select = TaskSelect { include: vec!["uniswap".to_string()], exclude: vec!["*_factory_events".to_string()] };
+  assert!(select.is_selected("uniswap", "uniswap_pair_events_usdc_weth"));
+  assert!(!select.is_selected("uniswap", "uniswap_factory_events"));
+  assert!(!select.is_selected("block_metrics", "block_metrics"));
+  assert!(TaskSelect::default().is_selected("block_metrics", "block_metrics"));
+}
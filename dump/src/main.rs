@@ -4,26 +4,57 @@ pub mod rpc;
 pub mod metrics;
 pub mod tasks;
 pub mod config;
+pub mod cli;
 
-use std::{path::Path, str::FromStr as _, sync::{atomic::AtomicU64, Arc}};
+use std::{path::{Path, PathBuf}, sync::{atomic::AtomicU64, Arc}};
 
 use anyhow::Result;
-use config::Config;
+use clap::Parser as _;
+use cli::{Cli, Command};
+use config::{Config, HeadTag};
+use ethers_core::types::{BlockNumber, H256};
 use ethers_providers::{JsonRpcClient, Middleware, Provider};
-use tasks::{pendle::PendleStage, uniswap::UniswapStage, RunConfig, RunEvent};
+use futures::{stream, StreamExt as _, TryStreamExt as _};
+use tasks::{pendle::PendleStage, uniswap::UniswapStage, Interrupted, RunConfig, RunEvent, Task, TaskFuture};
+use tokio::sync::watch;
 use tracing_subscriber::fmt::format::FmtSpan;
 
-async fn get_block_number<P: JsonRpcClient>(client: &Provider<P>) -> Result<u64> {
-  let block_number = client.get_block_number().await?;
-  Ok(block_number.as_u64())
+async fn get_block_number<P: JsonRpcClient>(client: &Provider<P>, config: &Config, stage: &Stage) -> Result<u64> {
+  if config.offline {
+    return Ok(stage.last_head());
+  }
+  let block_number = match config.head_tag {
+    HeadTag::Latest => client.get_block_number().await?,
+    tag => client.get_block(BlockNumber::from(tag)).await?.and_then(|i| i.number).ok_or_else(|| anyhow::anyhow!("block {tag:?} not exists"))?,
+  };
+  Ok(block_number.as_u64().saturating_sub(config.confirmations))
 }
 
+/// only the most recent rounds are kept, a reorg deeper than this can't be repaired
+const MAX_BLOCK_HASHES: usize = 128;
+
+/// Hash of the last block indexed in a round, used to detect reorgs on next round.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BlockHash {
+  height: u64,
+  hash: H256,
+}
+
+/// Layout of the stage file, bumped with a step in [`migrate_stage`] whenever the layout changes.
+const STAGE_VERSION: u32 = 1;
+
 #[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Stage {
+  /// stage files written before versioning are version 0
+  #[serde(default)]
+  version: u32,
   _cut: Option<u64>,
   #[serde(default)]
   block_metrics: Arc<AtomicU64>,
 
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  block_hashes: Vec<BlockHash>,
+
   #[serde(flatten)]
   uniswap: UniswapStage,
 
@@ -31,6 +62,53 @@ pub struct Stage {
   pendle: PendleStage,
 }
 
+impl Stage {
+  pub fn tasks(&self) -> Vec<Task> {
+    let mut result = vec![Task::new("block_metrics", "block_metrics".to_string(), self.block_metrics.clone())];
+    result.extend(self.uniswap.tasks());
+    result.extend(self.pendle.tasks());
+    result
+  }
+
+  pub fn init_checkpoints(&self, cut: u64) {
+    self.uniswap.init_checkpoints(cut);
+    self.pendle.init_checkpoints(cut);
+  }
+
+  /// Check contract entries of the stage file, `created` is checked against `head` if known.
+  pub fn validate(&self, head: Option<u64>) -> Result<()> {
+    let mut errors = Vec::new();
+    self.uniswap.validate(head, &mut errors);
+    self.pendle.validate(head, &mut errors);
+    if !errors.is_empty() {
+      anyhow::bail!("invalid stage file:\n  {}", errors.join("\n  "));
+    }
+    Ok(())
+  }
+
+  /// Register new contracts found by factory tasks, returns how many are new.
+  pub fn register_pairs(&mut self, config: &Config) -> Result<usize> {
+    let count = self.uniswap.register_pairs(config)? + self.pendle.register_markets(config)?;
+    Ok(count)
+  }
+
+  /// `block_length` of the last round, or the furthest checkpoint if not recorded.
+  pub fn last_head(&self) -> u64 {
+    match self.block_hashes.last() {
+      Some(record) => record.height + 1,
+      None => self.tasks().iter().map(|i| i.checkpoint.load(std::sync::atomic::Ordering::SeqCst)).max().unwrap_or_default(),
+    }
+  }
+
+  pub fn record_block_hash(&mut self, height: u64, hash: H256) {
+    self.block_hashes.push(BlockHash { height, hash });
+    let len = self.block_hashes.len();
+    if len > MAX_BLOCK_HASHES {
+      self.block_hashes.drain(..len - MAX_BLOCK_HASHES);
+    }
+  }
+}
+
 pub struct DatasetName<'a> {
   name: &'a str,
   cut: u64,
@@ -47,18 +125,14 @@ impl<'a> DatasetName<'a> {
   pub fn tmp_filename(&self) -> String {
     format!("{}.tmp", self.filename())
   }
-  pub fn part_filename(&self) -> String {
-    format!("{}.part", self.filename())
+  /// A segment of an incomplete cut with blocks `start..end`, merged into the cut file once the cut is complete.
+  pub fn part_filename(&self, start: u64, end: u64) -> String {
+    format!("{}.{}-{}.part", self.filename(), start, end)
   }
-  pub fn from_string(name: &'a str) -> Option<(Self, &'a str)> {
-    let (name, rest) = if let Some(name) = name.strip_suffix(".tmp") {
-      (name, ".tmp")
-    } else if let Some(name) = name.strip_suffix(".part") {
-      (name, ".part")
-    } else {
-      (name, "")
-    };
-    let name = name.strip_suffix(".parquet")?;
+  /// Returns the dataset and the suffix after `.parquet`, which is empty for a cut file.
+  pub fn from_filename(name: &'a str) -> Option<(Self, &'a str)> {
+    let pos = name.rfind(".parquet")?;
+    let (name, rest) = (&name[..pos], &name[pos + ".parquet".len()..]);
     let mut split = name.rsplitn(2, '.');
     let idx = split.next()?.parse().ok()?;
     let mut split = split.next()?.rsplitn(2, '_');
@@ -67,25 +141,90 @@ impl<'a> DatasetName<'a> {
     assert_eq!(split.next(), None);
     Some((Self { name, cut, idx }, rest))
   }
+  /// Blocks of a part file from the suffix returned by [`Self::from_filename`],
+  /// the end is unknown for a part named by its start only, as written by older versions.
+  pub fn part_range(rest: &str) -> Option<(u64, Option<u64>)> {
+    let range = rest.strip_prefix('.')?.strip_suffix(".part")?;
+    match range.split_once('-') {
+      Some((start, end)) => Some((start.parse().ok()?, Some(end.parse().ok()?))),
+      None => Some((range.parse().ok()?, None)),
+    }
+  }
+}
+
+/// Upgrade a stage file written by an older version, one version at a time.
+/// Task families added since then need no step, they start from their serde defaults.
+fn migrate_stage(table: &mut toml::Table, version: u32) -> Result<()> {
+  for version in version..STAGE_VERSION {
+    match version {
+      // unversioned files have the same layout as version 1
+      0 => {}
+      _ => unreachable!("no migration from stage version {version}"),
+    }
+  }
+  table.insert("version".to_string(), toml::Value::Integer(STAGE_VERSION.into()));
+  Ok(())
 }
 
-fn load_stage<P: AsRef<Path>>(data_dir: P) -> Result<Stage> {
+/// Load the stage, an older stage file is migrated, and saved with a copy of the original if `save` is set.
+fn load_stage<P: AsRef<Path>>(data_dir: P, save: bool) -> Result<Stage> {
   let filename = data_dir.as_ref().join("stage.toml");
   let stage: Stage = match std::fs::read_to_string(&filename) {
     Ok(content) => {
-      toml::from_str::<Stage>(&content)?
+      let mut table = toml::from_str::<toml::Table>(&content)?;
+      let version = match table.get("version") {
+        Some(version) => version.as_integer().and_then(|i| u32::try_from(i).ok()).ok_or_else(|| anyhow::anyhow!("invalid stage version {version}"))?,
+        None => 0,
+      };
+      if version > STAGE_VERSION {
+        anyhow::bail!("stage file version {version} is newer than {STAGE_VERSION}, please upgrade dump");
+      }
+      let migrated = version < STAGE_VERSION;
+      if migrated {
+        info!(stage_file=%filename.display(), version, STAGE_VERSION, "migrate stage file");
+        migrate_stage(&mut table, version)?;
+      }
+      let stage = toml::Value::Table(table).try_into::<Stage>()?;
+      stage.validate(None).map_err(|e| anyhow::anyhow!("{}: {e}", filename.display()))?;
+      if migrated && save {
+        std::fs::copy(&filename, data_dir.as_ref().join(format!("stage.v{version}.toml")))?;
+        save_stage(data_dir.as_ref(), &stage)?;
+      }
+      stage
     }
     Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
       info!(stage_file=%filename.display(), "stage file not found, using default");
-      Stage::default()
+      Stage { version: STAGE_VERSION, ..Default::default() }
     }
     Err(e) => return Err(e)?,
   };
-  // save_stage(data_dir.as_ref(), &stage).ok();
 
   Ok(stage)
 }
 
+/// An exclusive lock on the data directory, released on drop or when the process exits in any way.
+pub struct DataDirLock(#[allow(unused)] std::fs::File);
+
+fn lock_data_dir<P: AsRef<Path>>(data_dir: P) -> Result<DataDirLock> {
+  use std::io::{Read as _, Seek as _, Write as _};
+  let filename = data_dir.as_ref().join("dump.lock");
+  let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&filename)?;
+  match file.try_lock() {
+    Ok(()) => {}
+    Err(std::fs::TryLockError::WouldBlock) => {
+      let mut pid = String::new();
+      file.read_to_string(&mut pid).ok();
+      anyhow::bail!("{} is locked by another dump process {}", data_dir.as_ref().display(), pid.trim());
+    }
+    Err(std::fs::TryLockError::Error(e)) => return Err(e)?,
+  }
+  // the pid is only informational, the lock is held by the open file
+  file.set_len(0)?;
+  file.rewind()?;
+  write!(file, "{}", std::process::id())?;
+  Ok(DataDirLock(file))
+}
+
 fn save_stage<P: AsRef<Path>>(data_dir: P, stage: &Stage) -> Result<()> {
   let filename = data_dir.as_ref().join("stage.toml.tmp");
   let content = toml::to_string(stage)?;
@@ -94,6 +233,189 @@ fn save_stage<P: AsRef<Path>>(data_dir: P, stage: &Stage) -> Result<()> {
   Ok(())
 }
 
+/// Compare recorded block hashes against the chain, newest first,
+/// and rewind every task to just after the newest block that is still canonical.
+async fn check_reorg<P: Middleware + 'static>(client: Arc<P>, config: &Config, stage: &mut Stage) -> Result<()> {
+  let mut reorg = false;
+  while let Some(record) = stage.block_hashes.last() {
+    let hash = rpc::eth::get_block_hash(client.clone(), record.height).await?;
+    if hash == Some(record.hash) {
+      break;
+    }
+    warn!(record.height, ?record.hash, ?hash, "block hash changed");
+    reorg = true;
+    stage.block_hashes.pop();
+  }
+  if !reorg {
+    return Ok(());
+  }
+  let Some(record) = stage.block_hashes.last() else {
+    anyhow::bail!("reorg deeper than recorded block hashes, please reset tasks manually");
+  };
+  let height = record.height + 1;
+  // otherwise tasks are fetched again from the orphaned responses
+  if let Some(cache_dir) = &config.cache_dir {
+    rpc::cache::invalidate(cache_dir, height)?;
+  }
+  for task in stage.tasks() {
+    task.rewind(&config.data_dir, config.cut, height)?;
+  }
+  save_stage(&config.data_dir, stage)?;
+  Ok(())
+}
+
+async fn run_stage<P: Middleware + 'static>(client: Arc<P>, config: &Config, stage: &Stage, shutdown: &watch::Receiver<bool>) -> Result<()>
+where P::Provider: rpc::batch::BatchClient {
+  // tasks run concurrently, only one of them writes stage file at a time
+  let save_lock = std::sync::Mutex::new(());
+  let default_event_listener = |_: RunEvent| {
+    let _guard = save_lock.lock().unwrap();
+    save_stage(&config.data_dir, stage).ok();
+    !*shutdown.borrow()
+  };
+
+  let mut tasks = Vec::<TaskFuture>::new();
+  // let magic_number = 98672723;
+  // let block_length = magic_number * (stage.block_metrics + 1) % (10 * DEFAULT_CUT) + stage.block_metrics;
+  // info!(block_length, "faking");
+  if config.tasks.is_selected("block_metrics", "block_metrics") {
+    let client = client.clone();
+    tasks.push(Box::pin(async move {
+      RunConfig::new(config, stage.block_metrics.clone(), "block_metrics", &|start, end|
+        metrics::block::fetch_blocks(client.clone(), start, end, config.concurrency, config.retries, config.block_batch)
+      ).run(|e: RunEvent| {
+        assert_eq!(Some(e.cut), stage._cut);
+        if e.len > 0 {
+          assert_eq!(e.len, e.checkpoint - e.start);
+        }
+        default_event_listener(e)
+      }).await
+    }));
+  }
+  tasks.extend(stage.uniswap.run_tasks(client.clone(), config, default_event_listener));
+  tasks.extend(stage.pendle.run_tasks(client.clone(), config, default_event_listener));
+
+  info!(tasks.len=tasks.len(), config.parallel_tasks, "run tasks");
+  // an interrupted task doesn't cancel the others, they stop after their chunk in hand as well
+  let interrupted = stream::iter(tasks).map(|task| async move {
+    match task.await {
+      Err(e) if e.is::<Interrupted>() => Ok(true),
+      result => result.map(|()| false),
+    }
+  }).buffer_unordered(config.parallel_tasks.max(1)).try_collect::<Vec<_>>().await?;
+  if interrupted.contains(&true) {
+    return Err(Interrupted.into());
+  }
+  Ok(())
+}
+
+async fn run_round<P: Middleware + 'static>(client: Arc<P>, config: &Config, stage: &mut Stage, shutdown: &watch::Receiver<bool>) -> Result<()>
+where P::Provider: rpc::batch::BatchClient {
+  check_reorg(client.clone(), config, stage).await?;
+  // hash is taken before fetching, so a reorg in the middle of a round is caught on next round
+  let height = config.block_length - 1;
+  let block = client.get_block(height).await?;
+  let (hash, timestamp) = (block.as_ref().and_then(|i| i.hash), block.map(|i| i.timestamp.as_u64()));
+  stage.register_pairs(config)?;
+  run_stage(client.clone(), config, stage, shutdown).await?;
+  // pairs created in this round are fetched in this round as well
+  if stage.register_pairs(config)? > 0 {
+    save_stage(&config.data_dir, stage)?;
+    run_stage(client, config, stage, shutdown).await?;
+  }
+  if let Some(hash) = hash {
+    stage.record_block_hash(height, hash);
+  }
+  if let Some(timestamp) = timestamp {
+    stage.pendle.finish_expired(config.block_length, timestamp);
+  }
+  save_stage(&config.data_dir, stage)?;
+  Ok(())
+}
+
+async fn run<P: rpc::batch::BatchClient + 'static>(client: Arc<Provider<P>>, mut config: Config, mut stage: Stage, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+  loop {
+    let block_length = match get_block_number(&client, &config, &stage).await {
+      Ok(block_length) => block_length,
+      Err(e) if config.follow => {
+        warn!(?e, "failed to get block number");
+        0
+      }
+      Err(e) => return Err(e),
+    };
+    config.confirmed.fetch_max(block_length, std::sync::atomic::Ordering::SeqCst);
+    if block_length > config.block_length {
+      // contracts added by hand may be mistyped, such a range would never be fetched
+      stage.validate(Some(block_length))?;
+      let previous = std::mem::replace(&mut config.block_length, block_length);
+      info!(config.block_length, "hello");
+      match run_round(client.clone(), &config, &mut stage, &shutdown).await {
+        Ok(()) => {}
+        Err(e) if e.is::<Interrupted>() => {
+          save_stage(&config.data_dir, &stage)?;
+          return Err(e);
+        }
+        // checkpoints are saved, so the failed range is retried on next poll
+        Err(e) if config.follow => {
+          error!(?e, config.block_length, "run stage failed");
+          config.block_length = previous;
+        }
+        // keep checkpoints of chunks committed so far
+        Err(e) => {
+          save_stage(&config.data_dir, &stage)?;
+          return Err(e);
+        }
+      }
+    }
+    if !config.follow {
+      break;
+    }
+    tokio::select! {
+      _ = tokio::time::sleep(std::time::Duration::from_secs(config.poll_interval)) => {}
+      _ = shutdown.changed() => {}
+    }
+    if *shutdown.borrow() {
+      return Err(Interrupted.into());
+    }
+  }
+  Ok(())
+}
+
+/// Exit code after SIGINT or SIGTERM, as a shell reports a process stopped by SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+async fn wait_signal() {
+  #[cfg(unix)]
+  {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("failed to listen SIGTERM");
+    tokio::select! {
+      _ = tokio::signal::ctrl_c() => {}
+      _ = terminate.recv() => {}
+    }
+  }
+  #[cfg(not(unix))]
+  tokio::signal::ctrl_c().await.ok();
+}
+
+/// The first SIGINT or SIGTERM asks tasks to stop after their chunk in hand,
+/// the second one exits at once, chunks in hand are lost but the stage is saved after each chunk committed.
+fn spawn_signal_handler(data_dir: PathBuf, fixture: Option<Arc<rpc::fixture::Fixture>>) -> watch::Receiver<bool> {
+  let (sender, receiver) = watch::channel(false);
+  tokio::spawn(async move {
+    wait_signal().await;
+    warn!("shutting down after chunks in hand, send the signal again to exit at once");
+    sender.send(true).ok();
+    wait_signal().await;
+    tasks::remove_tmp_files(&data_dir).ok();
+    if let Some(fixture) = fixture {
+      fixture.flush();
+    }
+    error!("exit at once");
+    std::process::exit(EXIT_INTERRUPTED);
+  });
+  receiver
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
   dotenvy::dotenv().ok();
@@ -101,41 +423,87 @@ async fn main() -> Result<()> {
     .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
     .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
     .init();
-  let mut config = Config::from_env();
-  info!(cwd=%std::env::current_dir().unwrap().display(), config.endpoint);
+  let cli = Cli::parse();
+  let mut config = Config::load(&cli.config)?;
+  if let Some(data_dir) = cli.data_dir {
+    config.data_dir = data_dir;
+  }
+  if let Some(endpoint) = cli.endpoint {
+    config.set_endpoint(endpoint);
+  }
+  if !cli.include.is_empty() {
+    config.tasks.include = cli.include;
+  }
+  config.tasks.exclude.extend(cli.exclude);
+  config.offline = cli.offline;
+  if config.offline && config.cache_dir.is_none() {
+    anyhow::bail!("offline mode needs cache_dir in config file");
+  }
+  info!(cwd=%std::env::current_dir().unwrap().display(), config.endpoint, ?config.endpoints);
   std::fs::create_dir_all(&config.data_dir)?;
-  let client = Arc::new(Provider::new(ethers_providers::Http::from_str(&config.endpoint)?));
-  config.block_length = get_block_number(&client).await?;
-  info!(config.block_length, "hello");
+  let mut endpoints = Vec::new();
+  for url in config.all_endpoints() {
+    let transport = rpc::transport::Transport::connect(url).await?;
+    endpoints.push((url.to_string(), rpc::limit::Limited::new(transport, config.endpoint_max_in_flight).with_rps(config.endpoint_rps)));
+  }
+  let health_check = endpoints.len() > 1 && !config.offline;
+  let transport = rpc::balance::Balanced::new(endpoints, config.max_lag);
+  if health_check {
+    transport.spawn_health_check(std::time::Duration::from_secs(config.poll_interval));
+  }
+  let transport = rpc::count::Counted::new(rpc::limit::Limited::new(transport, config.max_in_flight));
+  let transport = rpc::cache::Cached::new(transport, config.cache_dir.clone(), config.offline, config.confirmed.clone());
+  let transport = rpc::fixture::Recorder::new(transport, cli.record);
+  let fixture = transport.fixture();
+  let client = Arc::new(Provider::new(transport));
 
-  let mut stage = load_stage(&config.data_dir)?;
+  // status and plan only read the stage, so they could run next to a running dump
+  let read_only = matches!(cli.command, Some(Command::Status | Command::Plan));
+  let _lock = if read_only { None } else { Some(lock_data_dir(&config.data_dir)?) };
+  let mut stage = load_stage(&config.data_dir, !read_only)?;
   if let Some(cut) = stage._cut {
     config.cut = cut
   } else {
     stage._cut = Some(config.cut);
   }
-  info!(?stage);
-
-  let default_event_listener = |_: RunEvent| {
-    save_stage(&config.data_dir, &stage).ok();
-  };
+  stage.init_checkpoints(config.cut);
+  debug!(?stage);
 
-  // let magic_number = 98672723;
-  // let block_length = magic_number * (stage.block_metrics + 1) % (10 * DEFAULT_CUT) + stage.block_metrics;
-  // info!(block_length, "faking");
-  RunConfig::new(&config, stage.block_metrics.clone(), "block_metrics", &|start, end|
-    metrics::block::fetch_blocks(client.clone(), start, end)
-  ).run(|e: RunEvent| {
-    assert_eq!(Some(e.cut), stage._cut);
-    if e.len > 0 {
-      assert_eq!(e.len, e.checkpoint - e.start + e.start % e.cut);
+  match cli.command.unwrap_or(Command::Run { follow: false }) {
+    Command::Run { follow } => {
+      let issues = tasks::check::verify(&config.data_dir, config.cut, &stage)?;
+      // tmp files and parts merged already are never data, files of no task are kept as they may be removed from the stage on purpose
+      let (fatal, issues) = issues.into_iter().partition::<Vec<_>, _>(|i| i.is_fatal());
+      for issue in issues.iter().filter(|i| matches!(i, tasks::check::Issue::Orphan { merged: false, .. })) {
+        warn!(%issue, "data file not in the stage");
+      }
+      tasks::check::repair(&config.data_dir, config.cut, &stage, &issues)?;
+      if !fatal.is_empty() {
+        for issue in &fatal {
+          error!(%issue, "data files don't match the stage");
+        }
+        anyhow::bail!("{} issues in {}, run `dump check --repair` to fix them", fatal.len(), config.data_dir.display());
+      }
+      config.follow = follow;
+      info!(config.follow, ?config.tasks);
+      let data_dir = config.data_dir.clone();
+      let shutdown = spawn_signal_handler(data_dir.clone(), fixture.clone());
+      match run(client, config, stage, shutdown).await {
+        Err(e) if e.is::<Interrupted>() => {
+          tasks::remove_tmp_files(&data_dir)?;
+          if let Some(fixture) = fixture {
+            fixture.flush();
+          }
+          warn!("interrupted, stage saved");
+          std::process::exit(EXIT_INTERRUPTED);
+        }
+        result => result,
+      }
     }
-    default_event_listener(e);
-  }).await?;
-
-  stage.uniswap.run_tasks(client.clone(), &config, default_event_listener).await?;
-  stage.pendle.run_tasks(client.clone(), &config, default_event_listener).await?;
-
-  save_stage(&config.data_dir, &stage)?;
-  Ok(())
+    Command::Status => cli::status(&client, &config, &stage).await,
+    Command::Plan => cli::plan(&client, &config, &stage).await,
+    Command::Reset { pattern, block } => cli::reset(&config, &stage, &pattern, block),
+    Command::Check { repair } => cli::check(&config, &stage, repair),
+    Command::AddPair { kind, name, contract, created } => cli::add_pair(&config, &mut stage, kind, name, &contract, created),
+  }
 }
@@ -48,9 +48,10 @@ impl BlockMetric {
 }
 
 // https://stackoverflow.com/questions/73167416/creating-polars-dataframe-from-vecstruct
-pub async fn fetch_blocks<P: Middleware>(client: P, height_from: u64, height_to: u64) -> Result<DataFrame> {
+pub async fn fetch_blocks<P: Middleware>(client: P, height_from: u64, height_to: u64, concurrency: usize, retries: usize, batch: usize) -> Result<DataFrame>
+where <P as Middleware>::Error: 'static, P::Provider: rpc::batch::BatchClient {
   use polars::lazy::dsl::col;
-  let block_metrics = rpc::eth::get_blocks(client, height_from..height_to).await?;
+  let block_metrics = rpc::eth::get_blocks(client, height_from..height_to, concurrency, retries, batch).await?;
   debug!(block_metrics.len=?block_metrics.len(), height_from, height_to);
   let df = BlockMetric::to_df(&block_metrics)?;
   let agg = df.clone().lazy().select([
@@ -107,11 +107,10 @@ impl Log_CreateNewMarket {
   }
 }
 
-pub async fn fetch_pendle_market_factory<P: Middleware + 'static>(client: P, height_from: u64, height_to: u64) -> Result<DataFrame>
+pub async fn fetch_pendle_market_factory<P: Middleware + 'static>(client: P, height_from: u64, height_to: u64, page_size: &rpc::eth::LogsPageSize, concurrency: usize) -> Result<DataFrame>
 where P::Error: 'static {
-  const PAGE_SIZE: u64 = 10000;
   let client = Arc::new(client);
-  let logs = rpc::eth::get_logs(client.clone(), Some(consts::TOPIC_CreateNewMarket.clone()), None, height_from..height_to, PAGE_SIZE).await?;
+  let logs = rpc::eth::get_logs(client.clone(), &[*consts::TOPIC_CreateNewMarket], &[], height_from..height_to, page_size, concurrency).await?;
   debug!(logs.len=?logs.len(), height_from, height_to);
   let mut result = Vec::with_capacity(logs.len());
   for log in logs {
@@ -272,13 +271,24 @@ impl Log_Market {
   }
 }
 
-pub async fn fetch_pendle_market<P: Middleware>(client: P, height_from: u64, height_to: u64, pair: Address) -> Result<DataFrame>
+pub async fn fetch_pendle_markets<P: Middleware>(client: P, height_from: u64, height_to: u64, pairs: &[Address], page_size: &rpc::eth::LogsPageSize, concurrency: usize) -> Result<DataFrame>
 where P::Error: 'static {
-  const PAGE_SIZE: u64 = 2000;
-  let logs = rpc::eth::get_logs(client, None, Some(pair), height_from..height_to, PAGE_SIZE).await?;
+  let logs = rpc::eth::get_logs(client, &[], pairs, height_from..height_to, page_size, concurrency).await?;
   debug!(logs.len=?logs.len(), height_from, height_to);
   let logs = logs.into_iter().filter(|i| i.removed != Some(true)).map(LogMetric::from).filter_map(|i| Log_Market::try_from(i).ok()).collect::<Vec<_>>();
   let df = Log_Market::to_df(&logs)?;
   debug!("{}", df.head(None));
   Ok(df)
 }
+
+#[tokio::test]
+async fn test_fetch_pendle_market_factory() {
+  let client = ethers_providers::Provider::new(rpc::fixture::Replay::load(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/pendle_market_factory.json")).unwrap());
+  let page_size = rpc::eth::LogsPageSize::default();
+  page_size.init(1000);
+  let df = fetch_pendle_market_factory(client, 18969000, 18970000, &page_size, 1).await.unwrap();
+  assert_eq!(df.height(), 1);
+  assert_eq!(df.column("market_address").unwrap().str().unwrap().get(0), Some(consts::CONTRACT_PendleLPT26.to_checksum_hex().as_str()));
+  assert_eq!(df.column("pt_name").unwrap().str().unwrap().get(0), Some("PT-weETH-26DEC2024"));
+  assert_eq!(df.column("expiry").unwrap().u64().unwrap().get(0), Some(1735171200));
+}
@@ -78,10 +78,9 @@ impl Log_CreatePair {
   }
 }
 
-pub async fn fetch_uniswap_factory<P: Middleware>(client: P, height_from: u64, height_to: u64) -> Result<DataFrame>
+pub async fn fetch_uniswap_factory<P: Middleware>(client: P, height_from: u64, height_to: u64, page_size: &rpc::eth::LogsPageSize, concurrency: usize) -> Result<DataFrame>
 where P::Error: 'static {
-  const PAGE_SIZE: u64 = 10000;
-  let logs = rpc::eth::get_logs(client, Some(consts::TOPIC_PairCreated.clone()), None, height_from..height_to, PAGE_SIZE).await?;
+  let logs = rpc::eth::get_logs(client, &[*consts::TOPIC_PairCreated], &[], height_from..height_to, page_size, concurrency).await?;
   debug!(logs.len=?logs.len(), height_from, height_to);
   let logs = logs.into_iter().map(LogMetric::from).filter_map(|i| Log_CreatePair::try_from(i).ok()).collect::<Vec<_>>();
   let df = Log_CreatePair::to_df(&logs)?;
@@ -221,12 +220,23 @@ impl Log_Pair {
   }
 }
 
-pub async fn fetch_uniswap_pair<P: Middleware + 'static>(client: P, height_from: u64, height_to: u64, pair: Address) -> Result<DataFrame> {
-  const PAGE_SIZE: u64 = 2000;
-  let logs = rpc::eth::get_logs(client, None, Some(pair), height_from..height_to, PAGE_SIZE).await?;
+pub async fn fetch_uniswap_pairs<P: Middleware + 'static>(client: P, height_from: u64, height_to: u64, pairs: &[Address], page_size: &rpc::eth::LogsPageSize, concurrency: usize) -> Result<DataFrame> {
+  let logs = rpc::eth::get_logs(client, &[], pairs, height_from..height_to, page_size, concurrency).await?;
   debug!(logs.len=?logs.len(), height_from, height_to);
   let logs = logs.into_iter().filter(|i| i.removed != Some(true)).map(LogMetric::from).filter_map(|i| Log_Pair::try_from(i).ok()).collect::<Vec<_>>();
   let df = Log_Pair::to_df(&logs)?;
   debug!("{}", df.head(None));
   Ok(df)
 }
+
+#[tokio::test]
+async fn test_fetch_uniswap_pairs() {
+  let client = ethers_providers::Provider::new(rpc::fixture::Replay::load(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/uniswap_v2_pairs.json")).unwrap());
+  let page_size = rpc::eth::LogsPageSize::default();
+  page_size.init(1000);
+  let df = fetch_uniswap_pairs(client, 10008500, 10008600, &[*consts::CONTRACT_UniswapV2_USDC_WETH], &page_size, 1).await.unwrap();
+  assert_eq!(df.height(), 5);
+  let actions = df.column("action").unwrap().str().unwrap().into_no_null_iter().collect::<Vec<_>>();
+  assert_eq!(actions, ["Transfer", "Sync", "Mint", "Sync", "Swap"]);
+  assert_eq!(df.column("amount1_out").unwrap().f64().unwrap().get(4), Some(4808184197466171911.0));
+}
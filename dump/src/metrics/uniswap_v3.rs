@@ -86,10 +86,9 @@ impl Log_PoolCreated {
   }
 }
 
-pub async fn fetch_factory<P: Middleware>(client: P, height_from: u64, height_to: u64) -> Result<DataFrame>
+pub async fn fetch_factory<P: Middleware>(client: P, height_from: u64, height_to: u64, page_size: &rpc::eth::LogsPageSize, concurrency: usize) -> Result<DataFrame>
 where P::Error: 'static {
-  const PAGE_SIZE: u64 = 10000;
-  let logs = rpc::eth::get_logs(client, Some(consts::TOPIC_PoolCreated.clone()), None, height_from..height_to, PAGE_SIZE).await?;
+  let logs = rpc::eth::get_logs(client, &[*consts::TOPIC_PoolCreated], &[], height_from..height_to, page_size, concurrency).await?;
   debug!(logs.len=?logs.len(), height_from, height_to);
   let logs = logs.into_iter().map(LogMetric::from).filter_map(|i| Log_PoolCreated::try_from(i).ok()).collect::<Vec<_>>();
   let df = Log_PoolCreated::to_df(&logs)?;
@@ -234,10 +233,9 @@ impl Log_Pair {
   }
 }
 
-pub async fn fetch_uniswap_pair<P: Middleware>(client: P, height_from: u64, height_to: u64, pair: Address) -> Result<DataFrame>
+pub async fn fetch_uniswap_pairs<P: Middleware>(client: P, height_from: u64, height_to: u64, pairs: &[Address], page_size: &rpc::eth::LogsPageSize, concurrency: usize) -> Result<DataFrame>
 where P::Error: 'static {
-  const PAGE_SIZE: u64 = 2000;
-  let logs = rpc::eth::get_logs(client, None, Some(pair), height_from..height_to, PAGE_SIZE).await?;
+  let logs = rpc::eth::get_logs(client, &[], pairs, height_from..height_to, page_size, concurrency).await?;
   debug!(logs.len=?logs.len(), height_from, height_to);
   let logs = logs.into_iter().filter(|i| i.removed != Some(true)).map(LogMetric::from).filter_map(|i| Log_Pair::try_from(i).ok()).collect::<Vec<_>>();
   let df = Log_Pair::to_df(&logs)?;
@@ -0,0 +1,203 @@
+use std::{fmt::Debug, sync::{atomic::{AtomicU32, AtomicU64, AtomicUsize}, Arc}, time::Duration};
+
+use async_trait::async_trait;
+use ethers_core::types::U64;
+use ethers_providers::{JsonRpcClient, RpcError};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use super::batch::{BatchClient, BatchResult};
+
+/// An endpoint is skipped for a while after it fails, the delay is doubled for each failure in a row.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Endpoint<C> {
+  url: String,
+  client: C,
+  /// latest block number seen by the health check
+  head: AtomicU64,
+  failures: AtomicU32,
+  /// unix millis, the endpoint is unhealthy until then
+  retry_at: AtomicU64,
+}
+
+impl<C> Endpoint<C> {
+  fn is_healthy(&self, now: u64, min_head: u64) -> bool {
+    self.retry_at.load(std::sync::atomic::Ordering::SeqCst) <= now && self.head.load(std::sync::atomic::Ordering::SeqCst) >= min_head
+  }
+
+  fn on_success(&self) {
+    self.failures.store(0, std::sync::atomic::Ordering::SeqCst);
+    self.retry_at.store(0, std::sync::atomic::Ordering::SeqCst);
+  }
+
+  fn on_failure(&self, e: &dyn std::fmt::Display) {
+    let failures = self.failures.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let backoff = FAILURE_BACKOFF.saturating_mul(1 << failures.min(16)).min(MAX_FAILURE_BACKOFF);
+    self.retry_at.store(now_millis() + backoff.as_millis() as u64, std::sync::atomic::Ordering::SeqCst);
+    warn!(url=self.url, failures, ?backoff, "endpoint unhealthy: {}", e);
+  }
+}
+
+async fn check_heads<C: JsonRpcClient>(endpoints: &[Endpoint<C>]) {
+  for endpoint in endpoints {
+    match endpoint.client.request::<_, U64>("eth_blockNumber", ()).await {
+      Ok(head) => {
+        endpoint.head.store(head.as_u64(), std::sync::atomic::Ordering::SeqCst);
+        endpoint.on_success();
+      }
+      Err(e) => endpoint.on_failure(&e),
+    }
+  }
+  debug!(heads=?endpoints.iter().map(|i| (&i.url, i.head.load(std::sync::atomic::Ordering::SeqCst))).collect::<Vec<_>>());
+}
+
+/// Errors of an endpoint answering for blocks it doesn't have yet, e.g. "block range extends beyond current head block" from geth,
+/// the next endpoint may be ahead of it.
+const BEHIND_HEAD: &[&str] = &["beyond current head", "header not found", "unknown block", "after last accepted block"];
+
+fn is_behind_head<E: RpcError>(e: &E) -> bool {
+  e.as_error_response().is_some_and(|e| {
+    let message = e.message.to_lowercase();
+    BEHIND_HEAD.iter().any(|i| message.contains(i))
+  })
+}
+
+fn now_millis() -> u64 {
+  std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// A transport spreading requests over several endpoints in turn.
+/// An endpoint is skipped while it fails, or while its head is more than `max_lag` blocks behind the others,
+/// and is used again only if no endpoint is healthy. A request for blocks past the head of an endpoint goes to the next one.
+#[derive(Debug, Clone)]
+pub struct Balanced<C> {
+  endpoints: Arc<Vec<Endpoint<C>>>,
+  next: Arc<AtomicUsize>,
+  max_lag: u64,
+}
+
+impl<C: JsonRpcClient + 'static> Balanced<C> {
+  /// `endpoints` are pairs of url and client, it must not be empty.
+  pub fn new(endpoints: Vec<(String, C)>, max_lag: u64) -> Self {
+    assert!(!endpoints.is_empty(), "no endpoint");
+    let endpoints = endpoints.into_iter().map(|(url, client)| Endpoint {
+      url, client, head: Default::default(), failures: Default::default(), retry_at: Default::default(),
+    }).collect();
+    Self { endpoints: Arc::new(endpoints), next: Default::default(), max_lag }
+  }
+
+  /// Check the head of every endpoint every `interval` in background, as long as the transport is alive.
+  pub fn spawn_health_check(&self, interval: Duration) {
+    let endpoints = Arc::downgrade(&self.endpoints);
+    tokio::spawn(async move {
+      while let Some(endpoints) = endpoints.upgrade() {
+        check_heads(&endpoints).await;
+        drop(endpoints);
+        tokio::time::sleep(interval).await;
+      }
+    });
+  }
+
+  /// Endpoints in the order to try, healthy ones first.
+  fn order(&self) -> Vec<&Endpoint<C>> {
+    let now = now_millis();
+    let best = self.endpoints.iter().map(|i| i.head.load(std::sync::atomic::Ordering::SeqCst)).max().unwrap_or_default();
+    let min_head = best.saturating_sub(self.max_lag);
+    let next = self.next.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let mut result = (0..self.endpoints.len()).map(|i| &self.endpoints[(next + i) % self.endpoints.len()]).collect::<Vec<_>>();
+    result.sort_by_key(|i| !i.is_healthy(now, min_head));
+    result
+  }
+}
+
+#[async_trait]
+impl<C: JsonRpcClient + 'static> JsonRpcClient for Balanced<C> {
+  type Error = C::Error;
+
+  async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+  where
+    T: Debug + Serialize + Send + Sync,
+    R: DeserializeOwned + Send,
+  {
+    let mut last_error = None;
+    for endpoint in self.order() {
+      match endpoint.client.request(method, &params).await {
+        Ok(result) => {
+          endpoint.on_success();
+          return Ok(result);
+        }
+        // the endpoint is within `max_lag` but behind the block asked for, it is not a failure
+        Err(e) if is_behind_head(&e) => {
+          debug!(url=endpoint.url, method, "behind head: {}", e);
+          last_error = Some(e);
+        }
+        // the endpoint answered, the request itself is rejected
+        Err(e) if e.is_error_response() => return Err(e),
+        Err(e) => {
+          endpoint.on_failure(&e);
+          last_error = Some(e);
+        }
+      }
+    }
+    Err(last_error.expect("at least one endpoint"))
+  }
+}
+
+#[async_trait]
+impl<C: BatchClient + 'static> BatchClient for Balanced<C> {
+  async fn batch_request(&self, method: &str, params: Vec<Value>) -> Result<BatchResult, Self::Error> {
+    let mut last_error = None;
+    for endpoint in self.order() {
+      match endpoint.client.batch_request(method, params.clone()).await {
+        Ok(result) => {
+          endpoint.on_success();
+          return Ok(result);
+        }
+        Err(e) if is_behind_head(&e) => {
+          debug!(url=endpoint.url, method, "behind head: {}", e);
+          last_error = Some(e);
+        }
+        Err(e) if e.is_error_response() => return Err(e),
+        Err(e) => {
+          endpoint.on_failure(&e);
+          last_error = Some(e);
+        }
+      }
+    }
+    Err(last_error.expect("at least one endpoint"))
+  }
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct Answer(std::result::Result<Value, ethers_providers::JsonRpcError>);
+
+#[cfg(test)]
+#[async_trait]
+impl JsonRpcClient for Answer {
+  type Error = ethers_providers::ProviderError;
+
+  async fn request<T, R>(&self, _method: &str, _params: T) -> Result<R, Self::Error>
+  where
+    T: Debug + Serialize + Send + Sync,
+    R: DeserializeOwned + Send,
+  {
+    match &self.0 {
+      Ok(value) => Ok(serde_json::from_value(value.clone())?),
+      Err(e) => Err(ethers_providers::HttpClientError::from(e.clone()).into()),
+    }
+  }
+}
+
+#[tokio::test]
+async fn test_behind_head() {
+  let error = |message: &str| Answer(Err(ethers_providers::JsonRpcError { code: -32000, message: message.to_string(), data: None }));
+  let endpoints = |first| vec![("a".to_string(), first), ("b".to_string(), Answer(Ok(serde_json::json!([]))))];
+  let client = Balanced::new(endpoints(error("block range extends beyond current head block")), 8);
+  assert_eq!(client.request::<_, Vec<Value>>("eth_getLogs", ()).await.unwrap(), Vec::<Value>::new());
+  let client = Balanced::new(endpoints(error("invalid argument 0: hex string without 0x prefix")), 8);
+  assert!(client.request::<_, Vec<Value>>("eth_getLogs", ()).await.is_err());
+}
@@ -0,0 +1,75 @@
+use async_trait::async_trait;
+use ethers_providers::{HttpClientError, JsonRpcClient, JsonRpcError, ProviderError, RpcError as _};
+use serde_json::Value;
+
+use super::transport::Transport;
+
+/// Result of each request in a batch.
+pub type BatchResult = Vec<Result<Value, JsonRpcError>>;
+
+/// A transport sending many requests of one method in a single JSON-RPC batch.
+#[async_trait]
+pub trait BatchClient: JsonRpcClient {
+  /// Call `method` with each of `params`, an error of one request doesn't fail the others.
+  async fn batch_request(&self, method: &str, params: Vec<Value>) -> Result<BatchResult, Self::Error>;
+}
+
+pub fn item_error(message: String) -> JsonRpcError {
+  JsonRpcError { code: -32603, message, data: None }
+}
+
+#[derive(serde::Serialize)]
+struct Request<'a> {
+  jsonrpc: &'static str,
+  id: usize,
+  method: &'a str,
+  params: Value,
+}
+
+#[derive(serde::Deserialize)]
+struct Response {
+  id: usize,
+  result: Option<Value>,
+  error: Option<JsonRpcError>,
+}
+
+#[async_trait]
+impl BatchClient for Transport {
+  async fn batch_request(&self, method: &str, params: Vec<Value>) -> Result<BatchResult, Self::Error> {
+    let len = params.len();
+    let Self::Http(client, http) = self else {
+      // ws and ipc are cheap per request, send them one by one
+      let requests = params.into_iter().map(|params| async move {
+        match self.request::<_, Value>(method, params).await {
+          Ok(value) => Ok(value),
+          Err(e) => Err(e.as_error_response().cloned().unwrap_or_else(|| item_error(e.to_string()))),
+        }
+      });
+      return Ok(futures::future::join_all(requests).await);
+    };
+    let body = params.into_iter().enumerate().map(|(id, params)| Request { jsonrpc: "2.0", id, method, params }).collect::<Vec<_>>();
+    let response = http.post(client.url().clone()).header("content-type", "application/json").body(serde_json::to_vec(&body)?).send().await;
+    let response = response.and_then(|i| i.error_for_status()).map_err(HttpClientError::from)?;
+    let body = response.bytes().await.map_err(HttpClientError::from)?;
+    let responses = match serde_json::from_slice::<Value>(&body)? {
+      Value::Array(responses) => responses,
+      // the node refuses the whole batch
+      response => {
+        let response = serde_json::from_value::<Response>(response)?;
+        let error = response.error.unwrap_or_else(|| item_error("unexpected response to batch".to_string()));
+        return Err(ProviderError::from(HttpClientError::from(error)));
+      }
+    };
+    let mut result = vec![Err(item_error("missing in batch response".to_string())); len];
+    for response in responses {
+      let response = serde_json::from_value::<Response>(response)?;
+      if let Some(item) = result.get_mut(response.id) {
+        *item = match (response.result, response.error) {
+          (_, Some(error)) => Err(error),
+          (result, None) => Ok(result.unwrap_or_default()),
+        };
+      }
+    }
+    Ok(result)
+  }
+}
@@ -0,0 +1,410 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::Debug, path::{Path, PathBuf}, sync::{atomic::AtomicU64, Arc, Mutex}};
+
+use async_trait::async_trait;
+use ethers_core::{types::H256, utils::keccak256};
+use ethers_providers::{JsonRpcClient, ProviderError};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use super::batch::{item_error, BatchClient, BatchResult};
+
+/// A transport keeping raw responses of `eth_getBlockByNumber` and `eth_getLogs` on disk, so datasets could be decoded again without the node.
+///
+/// Blocks are keyed by their height and the hash of the request, logs by the hash of the filter of each address without its block range,
+/// and each response is saved as `{from}_{to}.json` under the filter, so a range is served by any responses covering it,
+/// and logs of several addresses are merged from their filters, however the addresses were grouped when fetched.
+/// Requests by block tag like `latest` are never cached, and in offline mode a request missing in cache fails.
+/// Only responses below `confirmed` are written, so blocks near the head that may be reorged are always asked again.
+/// Blocks without transactions are only asked for their hash, e.g. to detect reorgs, so they are served from cache only offline.
+/// Without `dir` every request goes to the inner transport.
+#[derive(Debug, Clone)]
+pub struct Cached<C> {
+  inner: C,
+  dir: Option<PathBuf>,
+  offline: bool,
+  confirmed: Arc<AtomicU64>,
+  /// cached ranges of logs by filter, as `from` to the furthest `to`,
+  /// a filter dir is listed on first use and then kept in sync with responses written
+  ranges: Arc<Mutex<HashMap<PathBuf, BTreeMap<u64, u64>>>>,
+}
+
+impl<C> Cached<C> {
+  pub fn new(inner: C, dir: Option<PathBuf>, offline: bool, confirmed: Arc<AtomicU64>) -> Self {
+    Self { inner, dir, offline, confirmed, ranges: Default::default() }
+  }
+
+  /// Cached ranges covering `from..=to` in order, `None` if any block is not covered.
+  fn covering(&self, dir: &Path, from: u64, to: u64) -> Result<Option<Vec<(u64, u64)>>, ProviderError> {
+    let mut ranges = self.ranges.lock().unwrap();
+    let ranges = match ranges.entry(dir.to_path_buf()) {
+      std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+      std::collections::hash_map::Entry::Vacant(entry) => entry.insert(list_ranges(dir)?),
+    };
+    let mut result = Vec::new();
+    let mut next = from;
+    while next <= to {
+      // the nearest response starting at or before `next` that covers it
+      let Some((&start, &end)) = ranges.range(..=next).rev().find(|(_, end)| **end >= next) else {
+        return Ok(None);
+      };
+      result.push((start, end));
+      next = end + 1;
+    }
+    Ok(Some(result))
+  }
+
+  fn add_range(&self, dir: &Path, from: u64, to: u64) {
+    if let Some(ranges) = self.ranges.lock().unwrap().get_mut(dir) {
+      let end = ranges.entry(from).or_default();
+      *end = (*end).max(to);
+    }
+  }
+
+  fn is_confirmed(&self, height: u64) -> bool {
+    height < self.confirmed.load(std::sync::atomic::Ordering::SeqCst)
+  }
+
+  /// Logs of `from..=to` of every filter dir merged in order, `None` if any of them is not covered.
+  fn cached_logs(&self, dirs: &[(Option<String>, PathBuf)], from: u64, to: u64) -> Result<Option<Vec<Value>>, ProviderError> {
+    let mut result = Vec::new();
+    for (_, dir) in dirs {
+      let Some(ranges) = self.covering(dir, from, to)? else { return Ok(None) };
+      let Some(logs) = read_logs(dir, &ranges, from, to)? else {
+        // listed again on next request
+        self.ranges.lock().unwrap().remove(dir);
+        return Ok(None);
+      };
+      result.extend(logs);
+    }
+    if dirs.len() > 1 {
+      result.sort_by_key(|log| (parse_height(log.get("blockNumber")), parse_height(log.get("logIndex"))));
+    }
+    Ok(Some(result))
+  }
+}
+
+impl<C: JsonRpcClient> Cached<C> {
+  /// `eth_getLogs` of `from..=to` with filter dirs from [`split_filter`], served from cache only if all of them are covered,
+  /// otherwise asked as a whole and saved for each address.
+  async fn request_logs(&self, params: &Value, dirs: &[(Option<String>, PathBuf)], from: u64, to: u64) -> Result<Value, ProviderError> {
+    if let Some(logs) = self.cached_logs(dirs, from, to)? {
+      trace!(method="eth_getLogs", %params, "cache hit");
+      return Ok(Value::Array(logs));
+    }
+    if self.offline {
+      return Err(ProviderError::CustomError(format!("eth_getLogs not in cache: {}", params)));
+    }
+    let value: Value = self.inner.request("eth_getLogs", params).await.map_err(Into::into)?;
+    if let (Value::Array(logs), true) = (&value, self.is_confirmed(to)) {
+      for (address, dir) in dirs {
+        let logs = logs.iter()
+          .filter(|log| address.is_none() || log.get("address").and_then(Value::as_str).map(str::to_lowercase) == *address)
+          .cloned().collect();
+        write_json(&dir.join(format!("{}_{}.json", from, to)), &Value::Array(logs))?;
+        self.add_range(dir, from, to);
+      }
+    }
+    Ok(value)
+  }
+}
+
+fn hash_key(method: &str, params: &Value) -> String {
+  format!("{:x}", H256::from(keccak256(format!("{}{}", method, params))))
+}
+
+/// A block is saved as `{height}_{key}.json` so blocks from a height on could be removed after a reorg,
+/// `{key}.json` is written by older versions and still read.
+fn block_filename(dir: &Path, method: &str, params: &Value, height: u64) -> PathBuf {
+  let key = hash_key(method, params);
+  let legacy = dir.join(method).join(format!("{}.json", key));
+  if legacy.exists() {
+    return legacy;
+  }
+  dir.join(method).join(format!("{}_{}.json", height, key))
+}
+
+/// Remove cached blocks and logs from `height` on, which are orphaned by a reorg.
+/// A response of logs is removed as a whole if it reaches `height`, and blocks written by older versions are read for their number.
+pub fn invalidate(dir: &Path, height: u64) -> anyhow::Result<()> {
+  let reaches = |filename: &Path| -> anyhow::Result<bool> {
+    let Some(name) = filename.file_name().and_then(|i| i.to_str()).and_then(|i| i.strip_suffix(".json")) else { return Ok(false) };
+    Ok(match name.split_once('_') {
+      Some((_, end)) if filename.parent().and_then(|i| i.parent()).is_some_and(|i| i.ends_with("eth_getLogs")) => end.parse::<u64>().is_ok_and(|end| end >= height),
+      Some((start, _)) => start.parse::<u64>().is_ok_and(|start| start >= height),
+      None => parse_height(read_json(filename)?.get("number")).is_some_and(|i| i >= height),
+    })
+  };
+  let mut files = Vec::new();
+  if let Ok(entries) = std::fs::read_dir(dir.join("eth_getBlockByNumber")) {
+    for entry in entries {
+      files.push(entry?.path());
+    }
+  }
+  if let Ok(entries) = std::fs::read_dir(dir.join("eth_getLogs")) {
+    for entry in entries {
+      for entry in std::fs::read_dir(entry?.path())? {
+        files.push(entry?.path());
+      }
+    }
+  }
+  let mut count = 0;
+  for filename in files {
+    if reaches(&filename)? {
+      std::fs::remove_file(&filename)?;
+      count += 1;
+    }
+  }
+  info!(dir=%dir.display(), height, count, "invalidate cache");
+  Ok(())
+}
+
+fn parse_height(value: Option<&Value>) -> Option<u64> {
+  u64::from_str_radix(value?.as_str()?.strip_prefix("0x")?, 16).ok()
+}
+
+fn read_json(filename: &Path) -> Result<Value, ProviderError> {
+  let content = std::fs::read(filename).map_err(|e| ProviderError::CustomError(format!("failed to read {}: {}", filename.display(), e)))?;
+  Ok(serde_json::from_slice(&content)?)
+}
+
+fn write_json(filename: &Path, value: &Value) -> Result<(), ProviderError> {
+  let write = || -> std::io::Result<()> {
+    std::fs::create_dir_all(filename.parent().expect("file in cache dir"))?;
+    let tmp_filename = filename.with_extension("json.tmp");
+    std::fs::write(&tmp_filename, serde_json::to_vec(value)?)?;
+    std::fs::rename(&tmp_filename, filename)
+  };
+  write().map_err(|e| ProviderError::CustomError(format!("failed to write {}: {}", filename.display(), e)))
+}
+
+/// Filters of each address in `filter` without its block range, so an address is keyed the same whether it's asked alone or with others.
+/// A filter without address is kept as a whole, with `None` as its address.
+fn split_filter(mut filter: Value) -> Vec<(Option<String>, Value)> {
+  let Some(object) = filter.as_object_mut() else { return vec![(None, filter)] };
+  object.remove("fromBlock");
+  object.remove("toBlock");
+  let mut addresses = match object.get("address") {
+    Some(Value::String(address)) => vec![address.to_lowercase()],
+    Some(Value::Array(addresses)) if !addresses.is_empty() => match addresses.iter().map(|i| i.as_str().map(str::to_lowercase)).collect() {
+      Some(addresses) => addresses,
+      None => return vec![(None, filter)],
+    },
+    _ => return vec![(None, filter)],
+  };
+  addresses.sort();
+  addresses.dedup();
+  addresses.into_iter().map(|address| {
+    let mut filter = filter.clone();
+    filter["address"] = Value::String(address.clone());
+    (Some(address), filter)
+  }).collect()
+}
+
+/// Whether a `eth_getBlockByNumber` request asks for full transactions.
+fn has_txs(params: &Value) -> bool {
+  params.get(1).and_then(Value::as_bool).unwrap_or_default()
+}
+
+/// Ranges of responses saved under a filter dir, see [`Cached::covering`].
+fn list_ranges(dir: &Path) -> Result<BTreeMap<u64, u64>, ProviderError> {
+  let mut ranges = BTreeMap::new();
+  let Ok(entries) = std::fs::read_dir(dir) else { return Ok(ranges) };
+  for entry in entries {
+    let filename = entry.map_err(|e| ProviderError::CustomError(e.to_string()))?.file_name();
+    let Some((start, end)) = filename.to_str().and_then(|i| i.strip_suffix(".json")).and_then(|i| i.split_once('_')) else { continue };
+    if let (Ok(start), Ok(end)) = (start.parse::<u64>(), end.parse::<u64>()) {
+      let furthest = ranges.entry(start).or_default();
+      *furthest = end.max(*furthest);
+    }
+  }
+  Ok(ranges)
+}
+
+/// Logs of `from..=to` from responses of `ranges`, as returned by [`Cached::covering`].
+/// `None` if a response is gone, e.g. removed by [`invalidate`].
+fn read_logs(dir: &Path, ranges: &[(u64, u64)], from: u64, to: u64) -> Result<Option<Vec<Value>>, ProviderError> {
+  let mut result = Vec::new();
+  let mut next = from;
+  for &(start, end) in ranges {
+    let filename = dir.join(format!("{}_{}.json", start, end));
+    if !filename.exists() {
+      return Ok(None);
+    }
+    let Value::Array(logs) = read_json(&filename)? else {
+      return Ok(None);
+    };
+    let last = end.min(to);
+    result.extend(logs.into_iter().filter(|log| parse_height(log.get("blockNumber")).is_some_and(|i| next <= i && i <= last)));
+    next = last + 1;
+  }
+  Ok(Some(result))
+}
+
+#[async_trait]
+impl<C: JsonRpcClient> JsonRpcClient for Cached<C> {
+  type Error = ProviderError;
+
+  async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+  where
+    T: Debug + Serialize + Send + Sync,
+    R: DeserializeOwned + Send,
+  {
+    let Some(dir) = &self.dir else {
+      return self.inner.request(method, params).await.map_err(Into::into);
+    };
+    let params = serde_json::to_value(&params)?;
+    let (filename, height) = match method {
+      "eth_getBlockByNumber" => match parse_height(params.get(0)) {
+        Some(height) => (block_filename(dir, method, &params, height), height),
+        None => return self.inner.request(method, params).await.map_err(Into::into),
+      },
+      "eth_getLogs" => {
+        let filter = params.get(0).cloned().unwrap_or_default();
+        return match (parse_height(filter.get("fromBlock")), parse_height(filter.get("toBlock"))) {
+          (Some(from), Some(to)) => {
+            let dirs = split_filter(filter).into_iter().map(|(address, filter)| (address, dir.join(method).join(hash_key(method, &filter)))).collect::<Vec<_>>();
+            Ok(serde_json::from_value(self.request_logs(&params, &dirs, from, to).await?)?)
+          }
+          _ => self.inner.request(method, params).await.map_err(Into::into),
+        };
+      }
+      _ => return self.inner.request(method, params).await.map_err(Into::into),
+    };
+
+    if (self.offline || has_txs(&params)) && filename.exists() {
+      trace!(method, %params, "cache hit");
+      return Ok(serde_json::from_value(read_json(&filename)?)?);
+    }
+    if self.offline {
+      return Err(ProviderError::CustomError(format!("{} not in cache: {}", method, params)));
+    }
+    let value: Value = self.inner.request(method, &params).await.map_err(Into::into)?;
+    // a missing block is not cached, it may be there later
+    if !value.is_null() && self.is_confirmed(height) {
+      write_json(&filename, &value)?;
+    }
+    Ok(serde_json::from_value(value)?)
+  }
+}
+
+#[async_trait]
+impl<C: BatchClient> BatchClient for Cached<C> {
+  async fn batch_request(&self, method: &str, params: Vec<Value>) -> Result<BatchResult, Self::Error> {
+    let Some(dir) = &self.dir else {
+      return self.inner.batch_request(method, params).await.map_err(Into::into);
+    };
+    if method != "eth_getBlockByNumber" {
+      return self.inner.batch_request(method, params).await.map_err(Into::into);
+    }
+    let filenames = params.iter()
+      .map(|params| parse_height(params.get(0)).map(|height| (block_filename(dir, method, params, height), height)))
+      .collect::<Vec<_>>();
+    let mut result = Vec::with_capacity(params.len());
+    let mut missing = Vec::new();
+    for (i, filename) in filenames.iter().enumerate() {
+      match filename {
+        Some((filename, _)) if (self.offline || has_txs(&params[i])) && filename.exists() => result.push(Ok(read_json(filename)?)),
+        _ if self.offline => result.push(Err(item_error(format!("{} not in cache: {}", method, params[i])))),
+        _ => {
+          result.push(Ok(Value::Null));
+          missing.push(i);
+        }
+      }
+    }
+    if missing.is_empty() {
+      return Ok(result);
+    }
+    let responses = self.inner.batch_request(method, missing.iter().map(|&i| params[i].clone()).collect()).await.map_err(Into::into)?;
+    for (i, response) in missing.into_iter().zip(responses) {
+      if let (Ok(value), Some((filename, height))) = (&response, &filenames[i]) {
+        // a missing block is not cached, it may be there later
+        if !value.is_null() && self.is_confirmed(*height) {
+          write_json(filename, value)?;
+        }
+      }
+      result[i] = response;
+    }
+    Ok(result)
+  }
+}
+
+/// Answers each method with a value that could be changed, e.g. by a reorg.
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct Chain(Mutex<HashMap<String, Value>>);
+
+#[cfg(test)]
+#[async_trait]
+impl JsonRpcClient for Chain {
+  type Error = ProviderError;
+
+  async fn request<T, R>(&self, method: &str, _params: T) -> Result<R, Self::Error>
+  where
+    T: Debug + Serialize + Send + Sync,
+    R: DeserializeOwned + Send,
+  {
+    let value = self.0.lock().unwrap().get(method).cloned().unwrap_or_default();
+    Ok(serde_json::from_value(value)?)
+  }
+}
+
+#[tokio::test]
+async fn test_reorg() {
+  use serde_json::json;
+  let dir = std::env::temp_dir().join(format!("dump_test_reorg_{}", std::process::id()));
+  let chain = Arc::new(Chain::default());
+  let set_chain = |hash: &str| {
+    let mut answers = chain.0.lock().unwrap();
+    answers.insert("eth_getBlockByNumber".to_string(), json!({"number": "0x64", "hash": hash, "transactions": []}));
+    answers.insert("eth_getLogs".to_string(), json!([{"address": "0x01", "blockNumber": "0x64", "blockHash": hash}]));
+  };
+  // everything is confirmed, as with `confirmations = 0`
+  let client = Cached::new(chain.clone(), Some(dir.clone()), false, Arc::new(AtomicU64::new(200)));
+  let get_block = || client.request::<_, Value>("eth_getBlockByNumber", json!(["0x64", true]));
+  let get_logs = || client.request::<_, Value>("eth_getLogs", json!([{"address": "0x01", "fromBlock": "0x64", "toBlock": "0x96"}]));
+
+  set_chain("0xaa");
+  assert_eq!(get_block().await.unwrap()["hash"], "0xaa");
+  assert_eq!(get_logs().await.unwrap()[0]["blockHash"], "0xaa");
+  set_chain("0xbb");
+  // the orphaned responses are served until removed
+  assert_eq!(get_block().await.unwrap()["hash"], "0xaa");
+  assert_eq!(get_logs().await.unwrap()[0]["blockHash"], "0xaa");
+  invalidate(&dir, 100).unwrap();
+  assert_eq!(get_block().await.unwrap()["hash"], "0xbb");
+  assert_eq!(get_logs().await.unwrap()[0]["blockHash"], "0xbb");
+  // blocks below the reorg are kept
+  invalidate(&dir, 101).unwrap();
+  set_chain("0xcc");
+  assert_eq!(get_block().await.unwrap()["hash"], "0xbb");
+  std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_logs_by_address() {
+  use serde_json::json;
+  let dir = std::env::temp_dir().join(format!("dump_test_logs_by_address_{}", std::process::id()));
+  let chain = Arc::new(Chain::default());
+  chain.0.lock().unwrap().insert("eth_getLogs".to_string(), json!([
+    {"address": "0x01", "blockNumber": "0x64", "logIndex": "0x0"},
+    {"address": "0x02", "blockNumber": "0x64", "logIndex": "0x1"},
+    {"address": "0x01", "blockNumber": "0x65", "logIndex": "0x0"},
+  ]));
+  let get_logs = |client: &Cached<Arc<Chain>>, address: Value| {
+    let params = json!([{"address": address, "topics": ["0xff"], "fromBlock": "0x64", "toBlock": "0x65"}]);
+    let client = client.clone();
+    async move { client.request::<_, Vec<Value>>("eth_getLogs", params).await }
+  };
+  let client = Cached::new(chain.clone(), Some(dir.clone()), false, Arc::new(AtomicU64::new(200)));
+  assert_eq!(get_logs(&client, json!(["0x01", "0x02"])).await.unwrap().len(), 3);
+
+  // addresses grouped otherwise are served offline
+  let client = Cached::new(chain, Some(dir.clone()), true, Arc::new(AtomicU64::new(200)));
+  let logs = get_logs(&client, json!("0x01")).await.unwrap();
+  assert_eq!(logs.iter().map(|i| &i["blockNumber"]).collect::<Vec<_>>(), ["0x64", "0x65"]);
+  let logs = get_logs(&client, json!(["0x02", "0x01"])).await.unwrap();
+  let logs = logs.iter().map(|i| (i["address"].as_str().unwrap(), i["blockNumber"].as_str().unwrap())).collect::<Vec<_>>();
+  assert_eq!(logs, [("0x01", "0x64"), ("0x02", "0x64"), ("0x01", "0x65")]);
+  assert!(get_logs(&client, json!(["0x01", "0x03"])).await.is_err());
+  std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,73 @@
+use std::{collections::BTreeMap, fmt::Debug, future::Future, sync::{Arc, Mutex}};
+
+use async_trait::async_trait;
+use ethers_providers::JsonRpcClient;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use super::batch::{BatchClient, BatchResult};
+
+tokio::task_local! {
+  static COUNTS: Counts;
+}
+
+/// Requests by method, made while running a future in [`Self::scope`].
+#[derive(Debug, Default, Clone)]
+pub struct Counts(Arc<Mutex<BTreeMap<String, u64>>>);
+
+impl Counts {
+  pub async fn scope<F: Future>(&self, f: F) -> F::Output {
+    COUNTS.scope(self.clone(), f).await
+  }
+
+  pub fn total(&self) -> u64 {
+    self.0.lock().unwrap().values().sum()
+  }
+
+  fn add(&self, method: &str, count: u64) {
+    *self.0.lock().unwrap().entry(method.to_string()).or_default() += count;
+  }
+}
+
+impl std::fmt::Display for Counts {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for (i, (method, count)) in self.0.lock().unwrap().iter().enumerate() {
+      write!(f, "{}{}={}", if i == 0 { "" } else { " " }, method, count)?;
+    }
+    Ok(())
+  }
+}
+
+/// A transport that counts requests into the [`Counts`] of the current scope, requests out of any scope are not counted.
+#[derive(Debug, Clone)]
+pub struct Counted<C> {
+  inner: C,
+}
+
+impl<C> Counted<C> {
+  pub fn new(inner: C) -> Self {
+    Self { inner }
+  }
+}
+
+#[async_trait]
+impl<C: JsonRpcClient> JsonRpcClient for Counted<C> {
+  type Error = C::Error;
+
+  async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+  where
+    T: Debug + Serialize + Send + Sync,
+    R: DeserializeOwned + Send,
+  {
+    COUNTS.try_with(|counts| counts.add(method, 1)).ok();
+    self.inner.request(method, params).await
+  }
+}
+
+#[async_trait]
+impl<C: BatchClient> BatchClient for Counted<C> {
+  async fn batch_request(&self, method: &str, params: Vec<Value>) -> Result<BatchResult, Self::Error> {
+    COUNTS.try_with(|counts| counts.add(method, params.len() as u64)).ok();
+    self.inner.batch_request(method, params).await
+  }
+}
@@ -1,57 +1,217 @@
-use std::{ops::Range, sync::Arc};
+use std::{ops::Range, sync::{atomic::AtomicU64, Arc}};
 
 use anyhow::Result;
-use ethers_core::types::{Address, Filter, Log, H256};
+use ethers_core::types::{Address, Block, Filter, Log, Transaction, H256, U64};
 use ethers_providers::Middleware;
-use futures::{stream, StreamExt as _};
-use tokio::sync::Mutex;
+use futures::{stream, StreamExt as _, TryStreamExt as _};
 
 use crate::metrics::block::BlockMetric;
 
+use super::batch::BatchClient;
+
+/// Blocks that could not be fetched after retries, nothing of the range is returned.
+#[derive(Debug)]
+pub struct GetBlocksError {
+  /// height and the last error, sorted by height
+  pub failed: Vec<(u64, String)>,
+}
+
+impl std::fmt::Display for GetBlocksError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "failed to get {} blocks", self.failed.len())?;
+    for (height, e) in self.failed.iter().take(5) {
+      write!(f, ", {}: {}", height, e)?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for GetBlocksError {}
+
+/// Blocks of `heights` in one batch request, `None` for blocks failed or missing in the batch.
+async fn get_blocks_batch<C: BatchClient>(client: &C, heights: &[u64]) -> Vec<Option<Block<Transaction>>> {
+  let params = heights.iter().map(|i| serde_json::json!([U64::from(*i), true])).collect();
+  let items = match client.batch_request("eth_getBlockByNumber", params).await {
+    Ok(items) => items,
+    Err(e) => {
+      warn!(%e, heights.len=heights.len(), "batch request failed");
+      return vec![None; heights.len()];
+    }
+  };
+  items.into_iter().zip(heights).map(|(item, i)| match item {
+    Ok(value) => serde_json::from_value::<Option<Block<Transaction>>>(value).ok().flatten(),
+    Err(e) => {
+      debug!(i, %e, "batch item failed");
+      None
+    }
+  }).collect()
+}
+
+/// Blocks are fetched `batch` heights per request, blocks failed in a batch are retried one by one.
 #[tracing::instrument(level = "debug", skip_all, fields(height_range=format!("{}..{}", height_range.start, height_range.end)))]
-pub async fn get_blocks<P: Middleware>(client: P, height_range: Range<u64>) -> Result<Vec<BlockMetric>> {
-  let result = Arc::new(Mutex::new(vec![BlockMetric::default(); height_range.clone().count()]));
+pub async fn get_blocks<P: Middleware>(client: P, height_range: Range<u64>, concurrency: usize, retries: usize, batch: usize) -> Result<Vec<BlockMetric>>
+where <P as Middleware>::Error: 'static, P::Provider: BatchClient {
+  let mut result = vec![BlockMetric::default(); height_range.clone().count()];
+  let mut failed = Vec::new();
   let height_start = height_range.start;
-  stream::iter(height_range).for_each_concurrent(500, |i| {
+  let mut missing = height_range.collect::<Vec<_>>();
+  if batch > 1 {
+    let heights = std::mem::take(&mut missing);
+    let transport = client.provider().as_ref();
+    let mut batches = stream::iter(heights.chunks(batch)).map(|heights| async move {
+      (heights, get_blocks_batch(transport, heights).await)
+    }).buffer_unordered((concurrency / batch).max(1));
+    while let Some((heights, blocks)) = batches.next().await {
+      for (&i, block) in heights.iter().zip(blocks) {
+        match block {
+          Some(mut block) => {
+            block.number = block.number.or(Some(i.into()));
+            result[(i - height_start) as usize] = block.into();
+          }
+          None => missing.push(i),
+        }
+      }
+    }
+  }
+  let mut blocks = stream::iter(missing).map(|i| {
     let client = &client;
-    let result = result.clone();
     async move {
-      let mut block = client.get_block_with_txs(i).await.unwrap().ok_or_else(||anyhow::anyhow!("block not exists {i:?}")).unwrap();
-      block.number = block.number.or(Some(i.into()));
-      let target = block.into();
-      *result.lock().await.get_mut((i - height_start) as usize).unwrap() = target;
+      let block = super::retry(retries, || async move {
+        let mut block = client.get_block_with_txs(i).await?.ok_or_else(|| anyhow::anyhow!("block not exists {i:?}"))?;
+        block.number = block.number.or(Some(i.into()));
+        Ok(block)
+      }).await;
+      (i, block)
+    }
+  }).buffer_unordered(concurrency);
+  while let Some((i, block)) = blocks.next().await {
+    match block {
+      Ok(block) => result[(i - height_start) as usize] = block.into(),
+      Err(e) => failed.push((i, format!("{:#}", e))),
     }
-  }).await;
-  let result = result.lock().await.clone();
+  }
+  if !failed.is_empty() {
+    failed.sort();
+    return Err(GetBlocksError { failed }.into());
+  }
   Ok(result)
 }
 
-#[tracing::instrument(level = "debug", skip(client, height_range), fields(height_range=%format!("{}..{}", height_range.start, height_range.end)))]
-pub async fn get_logs<P: Middleware>(client: P, topic: Option<H256>, address: Option<Address>, height_range: Range<u64>, page_size: u64) -> Result<Vec<Log>>
+/// Pages with fewer logs than this are sparse, and the next page is doubled.
+const SPARSE_LOGS: usize = 1000;
+const MAX_PAGE_SIZE: u64 = 1000000;
+
+/// Blocks per `eth_getLogs` request, learned while paging and kept by the task across calls.
+#[derive(Debug, Default, Clone)]
+pub struct LogsPageSize(Arc<AtomicU64>);
+
+impl LogsPageSize {
+  /// Start from `size`, unless a size is learned already.
+  pub fn init(&self, size: u64) {
+    self.0.compare_exchange(0, size.clamp(1, MAX_PAGE_SIZE), std::sync::atomic::Ordering::SeqCst, std::sync::atomic::Ordering::SeqCst).ok();
+  }
+
+  pub fn get(&self) -> u64 {
+    self.0.load(std::sync::atomic::Ordering::SeqCst).max(1)
+  }
+
+  fn set(&self, size: u64) {
+    self.0.store(size.clamp(1, MAX_PAGE_SIZE), std::sync::atomic::Ordering::SeqCst);
+  }
+}
+
+/// Messages of providers rejecting a page for its size, e.g. "query returned more than 10000 results" from geth and reth,
+/// or "exceed maximum block range: 5000" and "Log response size exceeded" from hosted nodes.
+/// Other errors about the range like "invalid block range" are not retried with a smaller page.
+const PAGE_TOO_LARGE: &[&str] = &[
+  "query returned more than",
+  "query exceeds max results",
+  "exceed maximum block range",
+  "block range is too large",
+  "block range is too wide",
+  "block range too large",
+  "response size exceeded",
+  "response size should not greater than",
+  "too many logs",
+];
+
+fn is_page_too_large(e: &str) -> bool {
+  let e = e.to_lowercase();
+  PAGE_TOO_LARGE.iter().any(|i| e.contains(i))
+}
+
+#[tracing::instrument(level = "debug", skip(client, height_range, page_size), fields(height_range=%format!("{}..{}", height_range.start, height_range.end)))]
+/// Logs matching any of `topics` as topic0 from any of `addresses`, an empty list matches everything.
+pub async fn get_logs<P: Middleware>(client: P, topics: &[H256], addresses: &[Address], height_range: Range<u64>, page_size: &LogsPageSize, concurrency: usize) -> Result<Vec<Log>>
 where <P as Middleware>::Error: 'static {
   if height_range.start >= height_range.end {
     return Ok(Vec::new());
   }
   let filter = Filter::new().from_block(height_range.start).to_block(height_range.end.saturating_sub(1));
-  let filter = match address {
-    Some(address) => filter.address(address),
-    _ => filter,
+  let filter = match addresses {
+    [] => filter,
+    [address] => filter.address(*address),
+    addresses => filter.address(addresses.to_vec()),
   };
-  let filter = match topic {
-    Some(topic) => filter.topic0(topic),
-    _ => filter,
+  let filter = match topics {
+    [] => filter,
+    [topic] => filter.topic0(*topic),
+    topics => filter.topic0(topics.to_vec()),
   };
   info!(?filter);
   // const PAGE_SIZE: u64 = 10000;
+  // pages are cut lazily so they follow the learned size, and `buffered` keeps them in block order
+  let mut start = height_range.start;
+  let end = height_range.end;
+  let pages = std::iter::from_fn(|| {
+    (start < end).then(|| {
+      let page = start..std::cmp::min(start + page_size.get(), end);
+      start = page.end;
+      page
+    })
+  });
+  stream::iter(pages).map(|page| get_logs_page(&client, &filter, page, page_size)).buffered(concurrency.max(1)).try_concat().await
+}
+
+/// Fetch a page, split into smaller requests if the provider rejects it as too large.
+async fn get_logs_page<P: Middleware>(client: &P, filter: &Filter, height_range: Range<u64>, page_size: &LogsPageSize) -> Result<Vec<Log>>
+where <P as Middleware>::Error: 'static {
   let mut start = height_range.start;
   let end = height_range.end;
   let mut result = Vec::new();
   while start < end {
-    let to_block = std::cmp::min(start + page_size, end) - 1;
+    let size = page_size.get();
+    let to_block = std::cmp::min(start + size, end) - 1;
     trace!(start, to_block);
-    let logs = client.get_logs(&filter.clone().from_block(start).to_block(to_block)).await?;
-    result.extend(logs);
-    start += page_size;
+    match client.get_logs(&filter.clone().from_block(start).to_block(to_block)).await {
+      Ok(logs) => {
+        if logs.len() < SPARSE_LOGS && to_block + 1 - start == size {
+          page_size.set(size * 2);
+        }
+        result.extend(logs);
+        start = to_block + 1;
+      }
+      Err(e) if size > 1 && is_page_too_large(&e.to_string()) => {
+        debug!(start, size, "page too large: {}", e);
+        page_size.set(size / 2);
+      }
+      Err(e) => return Err(e.into()),
+    }
   }
   Ok(result)
 }
+
+pub async fn get_block_hash<P: Middleware>(client: P, height: u64) -> Result<Option<H256>>
+where <P as Middleware>::Error: 'static {
+  let block = client.get_block(height).await?;
+  Ok(block.and_then(|i| i.hash))
+}
+
+#[test]
+fn test_is_page_too_large() {
+  assert!(is_page_too_large("(code: -32005, message: query returned more than 10000 results, data: None)"));
+  assert!(is_page_too_large("Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range"));
+  assert!(is_page_too_large("exceed maximum block range: 5000"));
+  assert!(!is_page_too_large("invalid block range params"));
+  assert!(!is_page_too_large("block range extends beyond current head block"));
+}
@@ -0,0 +1,162 @@
+use std::{fmt::Debug, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use ethers_providers::{JsonRpcClient, ProviderError};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use super::batch::{item_error, BatchClient, BatchResult};
+
+/// A request and its response, `params` are compared as JSON when replaying.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Record {
+  pub method: String,
+  pub params: Value,
+  pub result: Value,
+}
+
+/// Records of a JSON file, shared by clones of a transport.
+/// A recording fixture is saved to `filename` once the last clone is dropped.
+#[derive(Debug, Default)]
+pub struct Fixture {
+  filename: Option<PathBuf>,
+  records: Mutex<Vec<Record>>,
+}
+
+impl Fixture {
+  pub fn load<P: AsRef<Path>>(filename: P) -> Result<Self> {
+    let filename = filename.as_ref();
+    let content = std::fs::read(filename).map_err(|e| anyhow::anyhow!("failed to read fixture {}: {e}", filename.display()))?;
+    let records = serde_json::from_slice(&content)?;
+    Ok(Self { filename: None, records: Mutex::new(records) })
+  }
+
+  fn find(&self, method: &str, params: &Value) -> Option<Value> {
+    self.records.lock().unwrap().iter().find(|i| i.method == method && &i.params == params).map(|i| i.result.clone())
+  }
+
+  fn push(&self, method: &str, params: Value, result: Value) {
+    let mut records = self.records.lock().unwrap();
+    if !records.iter().any(|i| i.method == method && i.params == params) {
+      records.push(Record { method: method.to_string(), params, result });
+    }
+  }
+
+  pub fn save<P: AsRef<Path>>(&self, filename: P) -> Result<()> {
+    let filename = filename.as_ref();
+    let tmp_filename = filename.with_extension("json.tmp");
+    std::fs::write(&tmp_filename, serde_json::to_vec_pretty(&*self.records.lock().unwrap())?)?;
+    std::fs::rename(&tmp_filename, filename)?;
+    Ok(())
+  }
+
+  /// Save a recording fixture to its `filename`, needed before `std::process::exit` which skips drop.
+  pub fn flush(&self) {
+    if let Some(filename) = &self.filename {
+      match self.save(filename) {
+        Ok(()) => info!(filename=%filename.display(), records=self.records.lock().unwrap().len(), "fixture saved"),
+        Err(e) => error!(filename=%filename.display(), ?e, "failed to save fixture"),
+      }
+    }
+  }
+}
+
+impl Drop for Fixture {
+  fn drop(&mut self) {
+    self.flush();
+  }
+}
+
+/// A transport serving every request from a [`Fixture`], so fetchers could run without a node.
+#[derive(Debug, Clone)]
+pub struct Replay {
+  fixture: Arc<Fixture>,
+}
+
+impl Replay {
+  pub fn load<P: AsRef<Path>>(filename: P) -> Result<Self> {
+    Ok(Self { fixture: Arc::new(Fixture::load(filename)?) })
+  }
+}
+
+#[async_trait]
+impl JsonRpcClient for Replay {
+  type Error = ProviderError;
+
+  async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+  where
+    T: Debug + Serialize + Send + Sync,
+    R: DeserializeOwned + Send,
+  {
+    let params = serde_json::to_value(&params)?;
+    let Some(result) = self.fixture.find(method, &params) else {
+      return Err(ProviderError::CustomError(format!("{} not in fixture: {}", method, params)));
+    };
+    Ok(serde_json::from_value(result)?)
+  }
+}
+
+#[async_trait]
+impl BatchClient for Replay {
+  async fn batch_request(&self, method: &str, params: Vec<Value>) -> Result<BatchResult, Self::Error> {
+    Ok(params.into_iter().map(|params| {
+      self.fixture.find(method, &params).ok_or_else(|| item_error(format!("{} not in fixture: {}", method, params)))
+    }).collect())
+  }
+}
+
+/// A transport saving responses of `inner` as a fixture for [`Replay`], batches are saved as single requests.
+/// Without `filename` every request just goes to the inner transport.
+#[derive(Debug, Clone)]
+pub struct Recorder<C> {
+  inner: C,
+  fixture: Option<Arc<Fixture>>,
+}
+
+impl<C> Recorder<C> {
+  pub fn new(inner: C, filename: Option<PathBuf>) -> Self {
+    let fixture = filename.map(|filename| Arc::new(Fixture { filename: Some(filename), records: Default::default() }));
+    Self { inner, fixture }
+  }
+
+  /// The fixture being recorded, if any.
+  pub fn fixture(&self) -> Option<Arc<Fixture>> {
+    self.fixture.clone()
+  }
+}
+
+#[async_trait]
+impl<C: JsonRpcClient> JsonRpcClient for Recorder<C> {
+  type Error = ProviderError;
+
+  async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+  where
+    T: Debug + Serialize + Send + Sync,
+    R: DeserializeOwned + Send,
+  {
+    let Some(fixture) = &self.fixture else {
+      return self.inner.request(method, params).await.map_err(Into::into);
+    };
+    let params = serde_json::to_value(&params)?;
+    let result: Value = self.inner.request(method, &params).await.map_err(Into::into)?;
+    fixture.push(method, params, result.clone());
+    Ok(serde_json::from_value(result)?)
+  }
+}
+
+#[async_trait]
+impl<C: BatchClient> BatchClient for Recorder<C> {
+  async fn batch_request(&self, method: &str, params: Vec<Value>) -> Result<BatchResult, Self::Error> {
+    let Some(fixture) = &self.fixture else {
+      return self.inner.batch_request(method, params).await.map_err(Into::into);
+    };
+    let result = self.inner.batch_request(method, params.clone()).await.map_err(Into::into)?;
+    for (params, item) in params.into_iter().zip(&result) {
+      if let Ok(value) = item {
+        fixture.push(method, params, value.clone());
+      }
+    }
+    Ok(result)
+  }
+}
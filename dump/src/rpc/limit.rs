@@ -0,0 +1,70 @@
+use std::{fmt::Debug, sync::{Arc, Mutex}, time::Duration};
+
+use async_trait::async_trait;
+use ethers_providers::JsonRpcClient;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{sync::{Semaphore, SemaphorePermit}, time::Instant};
+
+use super::batch::{BatchClient, BatchResult};
+
+/// A transport that shares one budget of in-flight requests between every task using it,
+/// and optionally spaces requests to a rate. Each call in a batch counts as a request, as providers bill them.
+#[derive(Debug, Clone)]
+pub struct Limited<C> {
+  inner: C,
+  in_flight: Arc<Semaphore>,
+  max_in_flight: usize,
+  /// time between two requests, and when the next one could be sent
+  rate: Option<(Duration, Arc<Mutex<Instant>>)>,
+}
+
+impl<C> Limited<C> {
+  pub fn new(inner: C, max_in_flight: usize) -> Self {
+    Self { inner, in_flight: Arc::new(Semaphore::new(max_in_flight)), max_in_flight, rate: None }
+  }
+
+  /// Wait for the rate of `count` requests, then hold as many slots of in-flight requests until the permit is dropped.
+  /// A batch larger than `max_in_flight` takes every slot.
+  async fn acquire(&self, count: usize) -> SemaphorePermit<'_> {
+    if let Some((interval, next)) = &self.rate {
+      let at = {
+        let mut next = next.lock().unwrap();
+        let at = (*next).max(Instant::now());
+        *next = at + *interval * count as u32;
+        at
+      };
+      tokio::time::sleep_until(at).await;
+    }
+    let count = count.clamp(1, self.max_in_flight.max(1)) as u32;
+    self.in_flight.acquire_many(count).await.expect("semaphore never closed")
+  }
+
+  /// At most `rps` requests per second, 0 means unlimited.
+  pub fn with_rps(self, rps: u32) -> Self {
+    let rate = (rps > 0).then(|| (Duration::from_secs(1) / rps, Arc::new(Mutex::new(Instant::now()))));
+    Self { rate, ..self }
+  }
+}
+
+#[async_trait]
+impl<C: JsonRpcClient> JsonRpcClient for Limited<C> {
+  type Error = C::Error;
+
+  async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+  where
+    T: Debug + Serialize + Send + Sync,
+    R: DeserializeOwned + Send,
+  {
+    let _permit = self.acquire(1).await;
+    self.inner.request(method, params).await
+  }
+}
+
+#[async_trait]
+impl<C: BatchClient> BatchClient for Limited<C> {
+  async fn batch_request(&self, method: &str, params: Vec<Value>) -> Result<BatchResult, Self::Error> {
+    let _permit = self.acquire(params.len()).await;
+    self.inner.batch_request(method, params).await
+  }
+}
@@ -1,2 +1,34 @@
 pub mod eth;
 pub mod contract;
+pub mod limit;
+pub mod balance;
+pub mod count;
+pub mod transport;
+pub mod cache;
+pub mod batch;
+pub mod fixture;
+
+use std::{future::Future, time::Duration};
+
+use anyhow::Result;
+
+/// Delay before the first retry, doubled after each failure.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Call `f` until it succeeds, or it has failed `retries + 1` times.
+pub async fn retry<T, Fut: Future<Output = Result<T>>>(retries: usize, mut f: impl FnMut() -> Fut) -> Result<T> {
+  let mut backoff = RETRY_BACKOFF;
+  let mut retried = 0;
+  loop {
+    match f().await {
+      Ok(result) => return Ok(result),
+      Err(e) if retried < retries => {
+        debug!(retried, ?backoff, "retry: {:#}", e);
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        retried += 1;
+      }
+      Err(e) => return Err(e),
+    }
+  }
+}
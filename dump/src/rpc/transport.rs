@@ -0,0 +1,95 @@
+use std::{fmt::Debug, sync::{Arc, RwLock}};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use ethers_providers::{Http, Ipc, JsonRpcClient, ProviderError, Ws, WsClientError};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::config::is_ipc_path;
+
+/// A transport picked by the endpoint: `ws://` or `wss://` for WebSocket, a socket path for IPC, http otherwise.
+#[derive(Debug, Clone)]
+pub enum Transport {
+  /// the client is shared with [`Http`] to send batches
+  Http(Http, reqwest::Client),
+  Ws(Reconnecting),
+  Ipc(Ipc),
+}
+
+/// Reconnects of a WebSocket connection before it is connected again from scratch.
+const WS_RECONNECTS: usize = 100;
+
+/// A WebSocket client connected again once its connection is gone for good,
+/// as [`Ws`] gives up after a few reconnects or when the node is down at the moment.
+#[derive(Debug, Clone)]
+pub struct Reconnecting {
+  endpoint: String,
+  /// the client and how many times it is connected, so concurrent failures connect only once
+  client: Arc<RwLock<(u64, Ws)>>,
+  connecting: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Reconnecting {
+  async fn connect(endpoint: &str) -> Result<Self, WsClientError> {
+    let client = Ws::connect_with_reconnects(endpoint, WS_RECONNECTS).await?;
+    Ok(Self { endpoint: endpoint.to_string(), client: Arc::new(RwLock::new((0, client))), connecting: Default::default() })
+  }
+
+  async fn reconnect(&self, generation: u64) {
+    let _guard = self.connecting.lock().await;
+    if self.client.read().unwrap().0 != generation {
+      return;
+    }
+    match Ws::connect_with_reconnects(self.endpoint.as_str(), WS_RECONNECTS).await {
+      Ok(client) => *self.client.write().unwrap() = (generation + 1, client),
+      Err(e) => warn!(endpoint=self.endpoint, "failed to reconnect: {}", e),
+    }
+  }
+
+  async fn request<T, R>(&self, method: &str, params: T) -> Result<R, ProviderError>
+  where
+    T: Debug + Serialize + Send + Sync,
+    R: DeserializeOwned + Send,
+  {
+    let (generation, client) = self.client.read().unwrap().clone();
+    match client.request(method, params).await {
+      // the request fails anyway, later ones go to the new connection
+      Err(e @ (WsClientError::DeadChannel | WsClientError::UnexpectedClose | WsClientError::TooManyReconnects)) => {
+        warn!(endpoint=self.endpoint, "websocket closed: {}", e);
+        self.reconnect(generation).await;
+        Err(e.into())
+      }
+      result => result.map_err(Into::into),
+    }
+  }
+}
+
+impl Transport {
+  pub async fn connect(endpoint: &str) -> Result<Self> {
+    Ok(if endpoint.starts_with("ws://") || endpoint.starts_with("wss://") {
+      Self::Ws(Reconnecting::connect(endpoint).await.map_err(|e| anyhow::anyhow!("failed to connect {endpoint:?}: {e}"))?)
+    } else if is_ipc_path(endpoint) {
+      Self::Ipc(Ipc::connect(endpoint).await.map_err(|e| anyhow::anyhow!("failed to connect {endpoint:?}: {e}"))?)
+    } else {
+      let client = reqwest::Client::new();
+      Self::Http(Http::new_with_client(endpoint.parse::<reqwest::Url>()?, client.clone()), client)
+    })
+  }
+}
+
+#[async_trait]
+impl JsonRpcClient for Transport {
+  type Error = ProviderError;
+
+  async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+  where
+    T: Debug + Serialize + Send + Sync,
+    R: DeserializeOwned + Send,
+  {
+    match self {
+      Self::Http(client, _) => client.request(method, params).await.map_err(Into::into),
+      Self::Ws(client) => client.request(method, params).await,
+      Self::Ipc(client) => client.request(method, params).await.map_err(Into::into),
+    }
+  }
+}
@@ -0,0 +1,214 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::atomic::AtomicU64};
+
+use polars::{io::SerReader as _, prelude::ParquetReader};
+
+use crate::{config::next_cut, DatasetName, Result, Stage};
+
+use super::{rewind, Task};
+
+/// Something on disk that doesn't match the checkpoint of its task.
+#[derive(Debug)]
+pub enum Issue {
+  /// a cut from `height` has no file while an earlier one has, repaired by rewinding the task to `height`
+  Gap { task: String, height: u64 },
+  /// a file with rows at or past the checkpoint, repaired by trimming the task to its checkpoint
+  Ahead { task: String, filename: PathBuf },
+  /// a part already merged into its cut file, removed on repair,
+  /// or a dataset file of no task or of another cut, which is only reported
+  Orphan { filename: PathBuf, merged: bool },
+  /// left by a write that never committed, removed on repair
+  Tmp { filename: PathBuf },
+}
+
+impl std::fmt::Display for Issue {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Gap { task, height } => write!(f, "gap\t{}\tmissing data from {}", task, height),
+      Self::Ahead { task, filename } => write!(f, "ahead\t{}\t{}", task, filename.display()),
+      Self::Orphan { filename, merged: true } => write!(f, "orphan\t{}\tmerged into its cut file", filename.display()),
+      Self::Orphan { filename, merged: false } => write!(f, "orphan\t{}\tnot in stage", filename.display()),
+      Self::Tmp { filename } => write!(f, "tmp\t{}", filename.display()),
+    }
+  }
+}
+
+impl Issue {
+  /// Gaps and files ahead of the checkpoint need a look before running, other issues are removed or only reported by [`repair`].
+  pub fn is_fatal(&self) -> bool {
+    matches!(self, Self::Gap { .. } | Self::Ahead { .. })
+  }
+}
+
+/// Cut index, blocks of a part or `None` for a cut file, and the path of a dataset file.
+type DatasetFile = (usize, Option<(u64, Option<u64>)>, PathBuf);
+
+fn max_height(filename: &Path) -> Result<Option<u64>> {
+  let df = ParquetReader::new(std::fs::File::open(filename)?).with_columns(Some(vec!["height".to_string()])).finish()?;
+  Ok(df.column("height")?.max::<u64>()?)
+}
+
+/// Files of the task on disk, checked against its checkpoint.
+fn verify_task(data_dir: &Path, cut: u64, task: &Task, files: &[DatasetFile], issues: &mut Vec<Issue>) -> Result<()> {
+  let checkpoint = task.checkpoint.load(std::sync::atomic::Ordering::SeqCst);
+  let first = task.start / cut * cut;
+  for (idx, part, filename) in files {
+    let start = part.map_or(*idx as u64 * cut, |(start, _)| start);
+    let complete = next_cut(*idx as u64 * cut, cut) <= checkpoint;
+    if start >= checkpoint {
+      issues.push(Issue::Ahead { task: task.name.clone(), filename: filename.clone() });
+    } else if part.is_some() && complete && data_dir.join(DatasetName::new(&task.name, cut, *idx).filename()).exists() {
+      issues.push(Issue::Orphan { filename: filename.clone(), merged: true });
+    } else if !complete {
+      // the stage was saved before the last chunk was committed, rows are read if the end is not in the name
+      let ahead = match part.and_then(|(_, end)| end) {
+        Some(end) => end > checkpoint,
+        None => max_height(filename)?.is_some_and(|i| i >= checkpoint),
+      };
+      if ahead {
+        issues.push(Issue::Ahead { task: task.name.clone(), filename: filename.clone() });
+      }
+    }
+  }
+  // the checkpoint may have been moved forward by hand with nothing below it, so data is looked for from the earliest file on
+  let earliest = files.iter().map(|(idx, part, _)| part.map_or(*idx as u64 * cut, |(start, _)| start)).filter(|i| *i < checkpoint).min();
+  let Some(earliest) = earliest else { return Ok(()) };
+  let mut start = earliest.max(first);
+  while start < checkpoint {
+    let idx = (start / cut) as usize;
+    let cut_end = next_cut(start, cut);
+    let cut_start = idx as u64 * cut;
+    let has_cut_file = files.iter().any(|(i, part, _)| *i == idx && part.is_none());
+    let parts = files.iter().filter(|(i, _, _)| *i == idx).filter_map(|(_, part, _)| *part);
+    // a complete cut is in its cut file, an incomplete one in parts following each other up to the checkpoint
+    let end = if start == cut_start && has_cut_file && cut_end <= checkpoint {
+      Some(cut_end)
+    } else if let Some((_, end)) = parts.clone().find(|(part_start, _)| *part_start == start) {
+      end
+    } else if start == cut_start && has_cut_file {
+      // the cut file of an incomplete cut, as written by older versions
+      None
+    } else {
+      issues.push(Issue::Gap { task: task.name.clone(), height: start });
+      break;
+    };
+    // where a file without end in its name stops is unknown, it is taken to reach the next part
+    let next_part = parts.filter(|(part_start, _)| *part_start > start).map(|(part_start, _)| part_start).min();
+    start = end.or(next_part).unwrap_or(cut_end).max(start + 1);
+  }
+  Ok(())
+}
+
+/// Check every task of the stage against its dataset files, and find `.tmp` files left over.
+pub fn verify(data_dir: &Path, cut: u64, stage: &Stage) -> Result<Vec<Issue>> {
+  let mut issues = Vec::new();
+  let mut datasets = BTreeMap::<String, Vec<DatasetFile>>::new();
+  let mut entries = std::fs::read_dir(data_dir)?.map(|i| Ok(i?.file_name())).collect::<Result<Vec<_>>>()?;
+  entries.sort();
+  for filename in entries {
+    let Some(filename) = filename.to_str() else { continue };
+    if filename.ends_with(".tmp") {
+      issues.push(Issue::Tmp { filename: data_dir.join(filename) });
+      continue;
+    }
+    let Some((dataset, rest)) = DatasetName::from_filename(filename) else { continue };
+    let part = DatasetName::part_range(rest);
+    if !rest.is_empty() && part.is_none() {
+      continue;
+    }
+    if dataset.cut != cut {
+      issues.push(Issue::Orphan { filename: data_dir.join(filename), merged: false });
+      continue;
+    }
+    datasets.entry(dataset.name.to_string()).or_default().push((dataset.idx, part, data_dir.join(filename)));
+  }
+  for task in stage.tasks() {
+    let files = datasets.remove(&task.name).unwrap_or_default();
+    verify_task(data_dir, cut, &task, &files, &mut issues)?;
+  }
+  for filename in datasets.into_values().flatten().map(|(_, _, filename)| filename) {
+    issues.push(Issue::Orphan { filename, merged: false });
+  }
+  Ok(issues)
+}
+
+/// Fix issues found by [`verify`], checkpoints are lowered in `stage` but the stage file is not saved.
+/// Files of no task are kept, they may be datasets removed from the stage on purpose.
+pub fn repair(data_dir: &Path, cut: u64, stage: &Stage, issues: &[Issue]) -> Result<()> {
+  let tasks = stage.tasks();
+  for issue in issues {
+    match issue {
+      Issue::Tmp { filename } | Issue::Orphan { filename, merged: true } => {
+        info!(filename=%filename.display(), "remove");
+        std::fs::remove_file(filename)?;
+      }
+      Issue::Orphan { merged: false, .. } | Issue::Gap { .. } => {}
+      Issue::Ahead { task, filename } => {
+        let Some(task) = tasks.iter().find(|i| &i.name == task) else { continue };
+        if !filename.exists() {
+          continue;
+        }
+        // rewind from past the file, so it is trimmed or removed while the checkpoint is kept
+        let checkpoint = task.checkpoint.load(std::sync::atomic::Ordering::SeqCst);
+        let (dataset, _) = DatasetName::from_filename(filename.file_name().and_then(|i| i.to_str()).unwrap_or_default()).expect("dataset file");
+        rewind(data_dir, &task.name, cut, &AtomicU64::new(next_cut(dataset.idx as u64 * cut, cut)), checkpoint)?;
+      }
+    }
+  }
+  // gaps go last, as a rewind only looks at files before the checkpoint
+  for issue in issues {
+    if let Issue::Gap { task, height } = issue {
+      if let Some(task) = tasks.iter().find(|i| &i.name == task) {
+        task.rewind(data_dir, cut, *height)?;
+      }
+    }
+  }
+  Ok(())
+}
+
+#[test]
+fn test_verify() {
+  use polars::{df, prelude::ParquetWriter};
+  let data_dir = std::env::temp_dir().join(format!("dump_test_verify_{}", std::process::id()));
+  std::fs::create_dir_all(&data_dir).unwrap();
+  let write = |filename: &str, heights: &[u64]| {
+    let mut df = df!("height" => heights).unwrap();
+    ParquetWriter::new(std::fs::File::create(data_dir.join(filename)).unwrap()).finish(&mut df).unwrap();
+  };
+  let stage = Stage::default();
+  stage.block_metrics.store(250, std::sync::atomic::Ordering::SeqCst);
+  write("block_metrics_100.0.parquet", &[0, 99]);
+  // cut 1 is missing
+  write("block_metrics_100.2.parquet.200.part", &[200, 260]);
+  write("block_metrics_100.3.parquet", &[300]);
+  write("block_metrics_100.2.parquet.tmp", &[]);
+  write("unknown_100.0.parquet", &[0]);
+  let issues = verify(&data_dir, 100, &stage).unwrap();
+  let issues = issues.iter().map(|i| i.to_string().replace(&data_dir.display().to_string(), "")).collect::<Vec<_>>();
+  assert_eq!(issues, [
+    "tmp\t/block_metrics_100.2.parquet.tmp",
+    "ahead\tblock_metrics\t/block_metrics_100.2.parquet.200.part",
+    "ahead\tblock_metrics\t/block_metrics_100.3.parquet",
+    "gap\tblock_metrics\tmissing data from 100",
+    "orphan\t/unknown_100.0.parquet\tnot in stage",
+  ]);
+  let issues = verify(&data_dir, 100, &stage).unwrap();
+  repair(&data_dir, 100, &stage, &issues).unwrap();
+  assert_eq!(stage.block_metrics.load(std::sync::atomic::Ordering::SeqCst), 100);
+  let issues = verify(&data_dir, 100, &stage).unwrap();
+  assert_eq!(issues.iter().map(|i| i.to_string()).collect::<Vec<_>>(), [format!("orphan\t{}\tnot in stage", data_dir.join("unknown_100.0.parquet").display())]);
+  // files of no task don't stop a run
+  assert!(!issues.iter().any(|i| i.is_fatal()));
+  // a part missing in the middle of an incomplete cut
+  write("block_metrics_100.1.parquet", &[100, 199]);
+  write("block_metrics_100.2.parquet.200-220.part", &[200]);
+  write("block_metrics_100.2.parquet.240-250.part", &[240]);
+  stage.block_metrics.store(250, std::sync::atomic::Ordering::SeqCst);
+  let issues = verify(&data_dir, 100, &stage).unwrap();
+  assert_eq!(issues.iter().filter(|i| i.is_fatal()).map(|i| i.to_string()).collect::<Vec<_>>(), ["gap\tblock_metrics\tmissing data from 220"]);
+  // data starting past the task start, e.g. the checkpoint moved forward by hand
+  for filename in ["block_metrics_100.0.parquet", "block_metrics_100.1.parquet", "block_metrics_100.2.parquet.200-220.part"] {
+    std::fs::remove_file(data_dir.join(filename)).unwrap();
+  }
+  assert!(!verify(&data_dir, 100, &stage).unwrap().iter().any(|i| i.is_fatal()));
+  std::fs::remove_dir_all(&data_dir).unwrap();
+}
@@ -1,11 +1,50 @@
 pub mod uniswap;
 pub mod pendle;
+pub mod check;
 
-use std::{future::Future, path::Path, sync::{atomic::AtomicU64, Arc}};
+use std::{future::Future, path::{Path, PathBuf}, pin::Pin, sync::{atomic::{AtomicBool, AtomicU64}, Arc}};
 
-use polars::{frame::DataFrame, io::SerReader as _, prelude::{ParquetReader, ParquetWriter}};
+use ethers_core::types::Address;
+use indexmap::IndexMap;
+use polars::{frame::DataFrame, io::SerReader as _, lazy::{dsl::{col, lit}, frame::IntoLazy as _}, prelude::{ParquetReader, ParquetWriter}};
 
-use crate::{config::{next_cut, Config}, Result};
+use crate::{config::{next_cut, Config}, metrics::ToChecksumHex as _, rpc::{count::Counts, eth::LogsPageSize}, DatasetName, Result};
+
+/// A pending run of one task, tasks are independent of each other so they could run in any order.
+pub type TaskFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + 'a>>;
+
+/// A dataset in the stage, `name` is also the prefix of its parquet files.
+#[derive(Debug, Clone)]
+pub struct Task {
+  pub family: &'static str,
+  pub name: String,
+  pub checkpoint: Arc<AtomicU64>,
+  pub finished: Arc<AtomicBool>,
+  /// block a fresh stage starts from, e.g. where the contract is created
+  pub start: u64,
+}
+
+impl Task {
+  pub fn new(family: &'static str, name: String, checkpoint: Arc<AtomicU64>) -> Self {
+    Self { family, name, checkpoint, finished: Default::default(), start: 0 }
+  }
+
+  pub fn with_start(self, start: u64) -> Self {
+    Self { start, ..self }
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.finished.load(std::sync::atomic::Ordering::SeqCst)
+  }
+
+  /// Rewind the dataset to `height`, a finished task is fetched again if anything is removed.
+  pub fn rewind(&self, data_dir: &Path, cut: u64, height: u64) -> Result<()> {
+    if self.checkpoint.load(std::sync::atomic::Ordering::SeqCst) > height {
+      self.finished.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+    rewind(data_dir, &self.name, cut, &self.checkpoint, height)
+  }
+}
 
 #[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ContractStage {
@@ -13,15 +52,38 @@ pub struct ContractStage {
   pub created: u64,
   #[serde(default, skip_serializing_if = "checkpoint_is_none")]
   pub checkpoint: Arc<AtomicU64>,
+  /// unix timestamp, the contract is finished once fetched past it
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub expiry: Option<u64>,
+  /// finished contracts are not fetched anymore
+  #[serde(default, skip_serializing_if = "is_unfinished")]
+  pub finished: Arc<AtomicBool>,
+  #[serde(skip)]
+  pub page_size: LogsPageSize,
 }
 pub fn checkpoint_is_none(data: &AtomicU64) -> bool {
   data.load(std::sync::atomic::Ordering::SeqCst) == 0
 }
+pub fn is_unfinished(data: &AtomicBool) -> bool {
+  !data.load(std::sync::atomic::Ordering::SeqCst)
+}
 impl ContractStage {
+  pub fn new(contract: String, created: u64) -> Self {
+    Self { contract, created, ..Default::default() }
+  }
+
   pub fn checkpoint(&self) -> u64 {
     self.checkpoint.load(std::sync::atomic::Ordering::SeqCst)
   }
 
+  pub fn is_finished(&self) -> bool {
+    self.finished.load(std::sync::atomic::Ordering::SeqCst)
+  }
+
+  pub fn task(&self, family: &'static str, name: String) -> Task {
+    Task { family, name, checkpoint: self.checkpoint.clone(), finished: self.finished.clone(), start: self.created }
+  }
+
   pub fn init_checkpoint(&self, cut: u64) {
     if self.checkpoint() == 0 {
       self.checkpoint.store(self.created / cut * cut, std::sync::atomic::Ordering::SeqCst);
@@ -29,9 +91,64 @@ impl ContractStage {
   }
 }
 
+/// Insert a contract found from factory events, unless the name or the contract is registered already.
+pub fn register_contract(contracts: &mut IndexMap<String, ContractStage>, cut: u64, name: String, contract: &str, created: u64) -> bool {
+  if contracts.contains_key(&name) || contracts.values().any(|i| i.contract.eq_ignore_ascii_case(contract)) {
+    return false;
+  }
+  info!(name, contract, created, "register contract");
+  let stage = ContractStage::new(contract.to_string(), created);
+  stage.init_checkpoint(cut);
+  contracts.insert(name, stage);
+  true
+}
+
+/// Parse a contract address, a mixed case address must match its EIP-55 checksum.
+pub fn parse_contract(contract: &str) -> Result<Address> {
+  let address = contract.parse::<Address>().map_err(|e| anyhow::anyhow!("invalid address {contract:?}: {e}"))?;
+  let hex = contract.strip_prefix("0x").unwrap_or(contract);
+  // all lower or all upper case carries no checksum
+  if hex.chars().any(|c| c.is_ascii_lowercase()) && hex.chars().any(|c| c.is_ascii_uppercase()) {
+    let checksum = address.to_checksum_hex();
+    if checksum[2..] != *hex {
+      anyhow::bail!("bad checksum of {contract:?}, expected {checksum:?}");
+    }
+  }
+  Ok(address)
+}
+
+/// Check contracts of the `table` in the stage file, each error starts with the TOML key of the entry.
+/// `created` is checked against `head` if known, the first block not yet produced.
+pub fn validate_contracts(table: &str, contracts: &IndexMap<String, ContractStage>, head: Option<u64>, errors: &mut Vec<String>) {
+  let mut seen = std::collections::HashMap::<Address, &str>::new();
+  for (name, stage) in contracts {
+    match parse_contract(&stage.contract) {
+      Ok(address) => if let Some(other) = seen.insert(address, name) {
+        errors.push(format!("{table}.{name}.contract: {} is registered as {table}.{other} already", stage.contract));
+      },
+      Err(e) => errors.push(format!("{table}.{name}.contract: {e}")),
+    }
+    if let Some(head) = head.filter(|head| stage.created >= *head) {
+      errors.push(format!("{table}.{name}.created: {} is past the head {}", stage.created, head.saturating_sub(1)));
+    }
+  }
+}
+
+/// A run stopped by its [`EventListener`], chunks fetched before are written and checkpointed.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "interrupted")
+  }
+}
+
+impl std::error::Error for Interrupted {}
+
 macro_rules! is_break {
   ($expr:expr) => {
-    if !$expr { anyhow::bail!("break"); }
+    if !$expr { return Err(Interrupted.into()); }
   };
 }
 
@@ -41,6 +158,7 @@ pub struct RunConfig<'a, Fn: Executor> {
   pub start: u64,
   pub end: u64,
   pub cut: u64,
+  pub batch: u64,
   pub name: &'a str,
   pub executor: &'a Fn,
 }
@@ -52,6 +170,7 @@ impl<'a, Fn: Executor> RunConfig<'a, Fn> {
       start: checkpoint.load(std::sync::atomic::Ordering::SeqCst),
       end: config.block_length,
       cut: config.cut,
+      batch: config.batch.max(1),
       checkpoint,
       name,
       executor
@@ -63,31 +182,282 @@ impl<'a, Fn: Executor> RunConfig<'a, Fn> {
     let mut start = config.start;
     let end = config.end;
     let cut = config.cut;
+    let counts = Counts::default();
     is_break!(tracker.on_event(RunEvent { start, checkpoint: start, len: 0, cut, end }));
     while start < end {
-      let checkpoint = next_cut(start, cut).min(end);
+      let checkpoint = next_cut(start, cut).min(end).min(start + config.batch);
       info!(config.start, config.end, config.name, "running for {}..{}", start, checkpoint);
       if start < checkpoint {
-        let tmp_filename = config.data_dir.join(format!("{}_{}.{}.parquet.tmp", config.name, cut, start/cut));
         // metrics::block::fetch_blocks(client, start, checkpoint).await?;
-        let mut df = config.executor.run(start, checkpoint).await?;
-        if start % cut != 0 {
-          let old_file = std::fs::File::open(tmp_filename.with_extension(""))?;
-          let old_df = ParquetReader::new(old_file).finish()?;
-          df = old_df.vstack(&df)?;
+        let df = counts.scope(config.executor.run(start, checkpoint)).await?;
+        let len = df.shape().0 as u64;
+        write_dataset(config.data_dir, config.name, cut, start, checkpoint, df)?.commit()?;
+        config.checkpoint.store(checkpoint, std::sync::atomic::Ordering::SeqCst);
+        // the listener saves the stage, so it goes after the chunk is committed and checkpointed
+        is_break!(tracker.on_event(RunEvent { start, checkpoint, len, cut, end }));
+      }
+      start = checkpoint;
+    }
+    if counts.total() > 0 {
+      info!(config.name, %counts, "requests");
+    }
+    Ok(())
+  }
+}
+
+/// Contracts of one kind at the same checkpoint, scanned together.
+/// The executor returns logs of all contracts, which are split into their own datasets by the `contract` column.
+pub struct BatchRunConfig<'a, Fn: Executor> {
+  pub data_dir: &'a Path,
+  pub start: u64,
+  pub end: u64,
+  pub cut: u64,
+  pub batch: u64,
+  /// dataset name and stage of each contract
+  pub contracts: Vec<(String, &'a ContractStage)>,
+  pub executor: &'a Fn,
+}
+
+impl<'a, Fn: Executor> BatchRunConfig<'a, Fn> {
+  pub fn new(config: &'a Config, contracts: Vec<(String, &'a ContractStage)>, executor: &'a Fn) -> Self {
+    Self {
+      data_dir: &config.data_dir,
+      start: contracts.first().map(|(_, i)| i.checkpoint()).unwrap_or(config.block_length),
+      end: config.block_length,
+      cut: config.cut,
+      batch: config.batch.max(1),
+      contracts,
+      executor,
+    }
+  }
+
+  pub async fn run(self, mut tracker: impl EventListener<RunEvent>) -> Result<()> {
+    let config = self;
+    let mut start = config.start;
+    let end = config.end;
+    let cut = config.cut;
+    let contracts = config.contracts.iter().map(|(_, i)| Ok(i.contract.parse::<Address>()?.to_checksum_hex())).collect::<Result<Vec<_>>>()?;
+    let counts = Counts::default();
+    is_break!(tracker.on_event(RunEvent { start, checkpoint: start, len: 0, cut, end }));
+    while start < end {
+      let checkpoint = next_cut(start, cut).min(end).min(start + config.batch);
+      info!(config.start, config.end, contracts.len=contracts.len(), "running for {}..{}", start, checkpoint);
+      if start < checkpoint {
+        let df = counts.scope(config.executor.run(start, checkpoint)).await?;
+        let len = df.shape().0 as u64;
+        let mut writes = Vec::new();
+        for ((name, _), contract) in config.contracts.iter().zip(&contracts) {
+          let df = df.clone().lazy().filter(col("contract").eq(lit(contract.as_str()))).collect()?;
+          writes.push(write_dataset(config.data_dir, name, cut, start, checkpoint, df)?);
+        }
+        for write in writes {
+          write.commit()?;
         }
-        let file = std::fs::File::create(&tmp_filename)?;
-        ParquetWriter::new(file).finish(&mut df)?;
-        is_break!(tracker.on_event(RunEvent { start, checkpoint, len: df.shape().0 as u64, cut, end }));
-        std::fs::rename(&tmp_filename, tmp_filename.with_extension(""))?;
+        for (_, stage) in &config.contracts {
+          stage.checkpoint.store(checkpoint, std::sync::atomic::Ordering::SeqCst);
+        }
+        is_break!(tracker.on_event(RunEvent { start, checkpoint, len, cut, end }));
       }
       start = checkpoint;
-      config.checkpoint.store(start, std::sync::atomic::Ordering::SeqCst);
+    }
+    if counts.total() > 0 {
+      let names = config.contracts.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+      info!(?names, %counts, "requests");
+    }
+    Ok(())
+  }
+}
+
+/// Group selected and unfinished contracts by checkpoint, each group is run by a [`BatchRunConfig`].
+pub fn batch_contracts<'a>(contracts: &'a IndexMap<String, ContractStage>, config: &Config, family: &str, prefix: &str) -> Vec<Vec<(String, &'a ContractStage)>> {
+  let mut result = IndexMap::<u64, Vec<_>>::new();
+  for (name, contract) in contracts {
+    let name = format!("{}_{}", prefix, name);
+    if contract.is_finished() || !config.tasks.is_selected(family, &name) {
+      continue;
+    }
+    contract.init_checkpoint(config.cut);
+    result.entry(contract.checkpoint()).or_default().push((name, contract));
+  }
+  result.into_values().collect()
+}
+
+/// Page size of a group from [`batch_contracts`], starting from `size`.
+/// It is kept by the first contract, which stays first as contracts of a group keep the same checkpoint.
+pub fn group_page_size(contracts: &[(String, &ContractStage)], size: u64) -> LogsPageSize {
+  let page_size = contracts.first().map(|(_, i)| i.page_size.clone()).unwrap_or_default();
+  page_size.init(size);
+  page_size
+}
+
+/// A dataset file written to `.tmp`, renamed in place by [`Self::commit`].
+#[must_use]
+struct PendingWrite {
+  tmp_filename: PathBuf,
+  filename: PathBuf,
+  /// parts merged into the file, removed once it is in place
+  compacted: Vec<PathBuf>,
+}
+
+impl PendingWrite {
+  fn commit(self) -> Result<()> {
+    std::fs::rename(&self.tmp_filename, &self.filename)?;
+    for part in self.compacted {
+      debug!(part=%part.display(), "compacted");
+      std::fs::remove_file(part)?;
     }
     Ok(())
   }
 }
 
+/// A complete cut goes into the cut file, merged with parts written before,
+/// otherwise the new rows are appended as a part and merged once the cut is complete.
+fn write_dataset(data_dir: &Path, name: &str, cut: u64, start: u64, checkpoint: u64, mut df: DataFrame) -> Result<PendingWrite> {
+  let dataset = DatasetName::new(name, cut, (start / cut) as usize);
+  let (filename, compacted) = if checkpoint == next_cut(start, cut) {
+    let (old_df, compacted) = read_parts(data_dir, &dataset, start)?;
+    if let Some(old_df) = old_df {
+      df = old_df.vstack(&df)?;
+    }
+    (dataset.filename(), compacted)
+  } else {
+    remove_stale_parts(data_dir, &dataset, start)?;
+    (dataset.part_filename(start, checkpoint), Vec::new())
+  };
+  let tmp_filename = data_dir.join(format!("{}.tmp", filename));
+  let file = std::fs::File::create(&tmp_filename)?;
+  ParquetWriter::new(file).finish(&mut df)?;
+  Ok(PendingWrite { tmp_filename, filename: data_dir.join(filename), compacted })
+}
+
+/// Part files of the cut with their blocks, sorted by their start block.
+fn list_parts(data_dir: &Path, dataset: &DatasetName) -> Result<Vec<(u64, Option<u64>, PathBuf)>> {
+  let mut parts = Vec::new();
+  for entry in std::fs::read_dir(data_dir)? {
+    let filename = entry?.file_name();
+    let Some(filename) = filename.to_str() else { continue };
+    let Some((i, rest)) = DatasetName::from_filename(filename) else { continue };
+    if i.name != dataset.name || i.cut != dataset.cut || i.idx != dataset.idx {
+      continue;
+    }
+    if let Some((start, end)) = DatasetName::part_range(rest) {
+      parts.push((start, end, data_dir.join(filename)));
+    }
+  }
+  parts.sort();
+  Ok(parts)
+}
+
+/// Parts at or after `start` are left by an interrupted run, and would be written again.
+fn remove_stale_parts(data_dir: &Path, dataset: &DatasetName, start: u64) -> Result<()> {
+  for (part_start, _, filename) in list_parts(data_dir, dataset)? {
+    if part_start > start {
+      warn!(filename=%filename.display(), start, "remove stale part");
+      std::fs::remove_file(filename)?;
+    }
+  }
+  Ok(())
+}
+
+/// Remove files left by writes that never committed, e.g. `.parquet.tmp` of a killed run.
+pub fn remove_tmp_files(data_dir: &Path) -> Result<()> {
+  for entry in std::fs::read_dir(data_dir)? {
+    let path = entry?.path();
+    if path.extension().is_some_and(|i| i == "tmp") {
+      warn!(file=%path.display(), "remove tmp file");
+      std::fs::remove_file(path)?;
+    }
+  }
+  Ok(())
+}
+
+/// Read the cut before `start` from its parts, also returns every file to remove once the cut file is written.
+/// A cut file of an incomplete cut, as written by older versions, is taken as the first part.
+fn read_parts(data_dir: &Path, dataset: &DatasetName, start: u64) -> Result<(Option<DataFrame>, Vec<PathBuf>)> {
+  let mut result: Option<DataFrame> = None;
+  let filename = data_dir.join(dataset.filename());
+  if start > dataset.idx as u64 * dataset.cut && filename.exists() {
+    result = Some(ParquetReader::new(std::fs::File::open(&filename)?).finish()?);
+  }
+  let mut files = Vec::new();
+  for (part_start, _, filename) in list_parts(data_dir, dataset)? {
+    if part_start < start {
+      let df = ParquetReader::new(std::fs::File::open(&filename)?).finish()?;
+      result = Some(match result {
+        Some(result) => result.vstack(&df)?,
+        None => df,
+      });
+    }
+    files.push(filename);
+  }
+  Ok((result, files))
+}
+
+/// Drop everything at `height` and above from the dataset, and lower the checkpoint to `height`.
+/// The cut containing `height` is rewritten, files in it going past `height` become parts ending at it, later cuts are removed.
+pub fn rewind(data_dir: &Path, name: &str, cut: u64, checkpoint: &AtomicU64, height: u64) -> Result<()> {
+  let current = checkpoint.load(std::sync::atomic::Ordering::SeqCst);
+  if current <= height {
+    return Ok(());
+  }
+  info!(name, current, height, "rewind");
+  for idx in height / cut..=(current - 1) / cut {
+    let dataset = DatasetName::new(name, cut, idx as usize);
+    let mut files = list_parts(data_dir, &dataset)?;
+    // the cut file of an incomplete cut is written by older versions, where it ends is unknown
+    let cut_end = next_cut(idx * cut, cut);
+    files.insert(0, (idx * cut, (current >= cut_end).then_some(cut_end), data_dir.join(dataset.filename())));
+    for (start, end, filename) in files {
+      if !filename.exists() {
+        continue;
+      }
+      if start < height {
+        let target = match end {
+          Some(end) if end > height => data_dir.join(dataset.part_filename(start, height)),
+          _ => filename.clone(),
+        };
+        let tmp_filename = data_dir.join(format!("{}.tmp", target.file_name().unwrap().to_string_lossy()));
+        let df = ParquetReader::new(std::fs::File::open(&filename)?).finish()?;
+        let mut df = df.lazy().filter(col("height").lt(lit(height))).collect()?;
+        ParquetWriter::new(std::fs::File::create(&tmp_filename)?).finish(&mut df)?;
+        std::fs::rename(&tmp_filename, &target)?;
+        if target != filename {
+          std::fs::remove_file(&filename)?;
+        }
+      } else {
+        std::fs::remove_file(&filename)?;
+      }
+    }
+  }
+  checkpoint.store(height, std::sync::atomic::Ordering::SeqCst);
+  Ok(())
+}
+
+/// Read every cut file and part file of a dataset, in block order.
+pub fn read_dataset(data_dir: &Path, name: &str) -> Result<Option<DataFrame>> {
+  let mut files = Vec::new();
+  for entry in std::fs::read_dir(data_dir)? {
+    let filename = entry?.file_name();
+    let Some(filename) = filename.to_str() else { continue };
+    if let Some((dataset, rest)) = DatasetName::from_filename(filename) {
+      let part = DatasetName::part_range(rest);
+      if dataset.name == name && (rest.is_empty() || part.is_some()) {
+        files.push((dataset.idx, part, data_dir.join(filename)));
+      }
+    }
+  }
+  files.sort();
+  let mut result: Option<DataFrame> = None;
+  for (_, _, filename) in files {
+    let df = ParquetReader::new(std::fs::File::open(&filename)?).finish()?;
+    result = Some(match result {
+      Some(result) => result.vstack(&df)?,
+      None => df,
+    });
+  }
+  Ok(result)
+}
+
 pub trait Executor {
   fn run(&self, start: u64, end: u64) -> impl Future<Output = Result<DataFrame>>;
 }
@@ -104,10 +474,9 @@ pub trait EventListener<E> {
 impl<E> EventListener<E> for () {
   fn on_event(&mut self, _: E) -> bool {true}
 }
-impl<F: FnMut(E), E> EventListener<E> for F {
+impl<F: FnMut(E) -> bool, E> EventListener<E> for F {
   fn on_event(&mut self, event: E) -> bool {
-    self(event);
-    true
+    self(event)
   }
 }
 
@@ -119,3 +488,104 @@ pub struct RunEvent {
   pub cut: u64,
   pub end: u64,
 }
+
+/// Run uniswap pair events of USDC/WETH in `10008500..10008600` from the fixture, in batches of 50 blocks.
+#[cfg(test)]
+async fn run_fixture(data_dir: &Path, checkpoint: Arc<AtomicU64>, tracker: impl EventListener<RunEvent>) -> Result<()> {
+  use crate::{metrics::uniswap_v2, rpc};
+  let client = Arc::new(ethers_providers::Provider::new(rpc::fixture::Replay::load(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/uniswap_v2_pairs.json")).unwrap()));
+  std::fs::create_dir_all(data_dir).unwrap();
+  let page_size = rpc::eth::LogsPageSize::default();
+  page_size.init(1000);
+  let pairs = [*uniswap_v2::consts::CONTRACT_UniswapV2_USDC_WETH];
+  RunConfig {
+    data_dir,
+    checkpoint,
+    start: 10008500,
+    end: 10008600,
+    cut: 1000000,
+    batch: 50,
+    name: "uniswap_pair_events_usdc_weth",
+    executor: &|start, end| uniswap_v2::fetch_uniswap_pairs(client.clone(), start, end, &pairs, &page_size, 1),
+  }.run(tracker).await
+}
+
+#[tokio::test]
+async fn test_run() {
+  let data_dir = std::env::temp_dir().join(format!("dump_test_run_{}", std::process::id()));
+  let checkpoint = Arc::new(AtomicU64::new(10008500));
+  let mut events = Vec::new();
+  run_fixture(&data_dir, checkpoint.clone(), |e: RunEvent| {
+    events.push((e.checkpoint, e.len));
+    true
+  }).await.unwrap();
+  assert_eq!(checkpoint.load(std::sync::atomic::Ordering::SeqCst), 10008600);
+  assert_eq!(events, [(10008500, 0), (10008550, 3), (10008600, 2)]);
+  let df = read_dataset(&data_dir, "uniswap_pair_events_usdc_weth").unwrap().unwrap();
+  assert_eq!(df.height(), 5);
+  std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_run_interrupted() {
+  let data_dir = std::env::temp_dir().join(format!("dump_test_run_interrupted_{}", std::process::id()));
+  let checkpoint = Arc::new(AtomicU64::new(10008500));
+  let result = run_fixture(&data_dir, checkpoint.clone(), |e: RunEvent| {
+    // the stage saved by a listener is never behind the files
+    assert_eq!(checkpoint.load(std::sync::atomic::Ordering::SeqCst), e.checkpoint);
+    e.len == 0
+  }).await;
+  // the first chunk is written before stopping
+  assert!(result.unwrap_err().is::<Interrupted>());
+  assert_eq!(checkpoint.load(std::sync::atomic::Ordering::SeqCst), 10008550);
+  let df = read_dataset(&data_dir, "uniswap_pair_events_usdc_weth").unwrap().unwrap();
+  assert_eq!(df.height(), 3);
+  assert!(std::fs::read_dir(&data_dir).unwrap().all(|i| !i.unwrap().path().to_string_lossy().ends_with(".tmp")));
+  std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[test]
+fn test_rewind() {
+  use polars::df;
+  let data_dir = std::env::temp_dir().join(format!("dump_test_rewind_{}", std::process::id()));
+  std::fs::create_dir_all(&data_dir).unwrap();
+  let write = |filename: String, heights: &[u64]| {
+    let mut df = df!("height" => heights).unwrap();
+    ParquetWriter::new(std::fs::File::create(data_dir.join(filename)).unwrap()).finish(&mut df).unwrap();
+  };
+  let dataset = |idx| DatasetName::new("block_metrics", 100, idx);
+  write(dataset(0).filename(), &[0, 50, 99]);
+  write(dataset(1).filename(), &[100, 150, 199]);
+  write(dataset(2).part_filename(200, 220), &[200, 210]);
+  write(dataset(2).part_filename(220, 230), &[220]);
+  let checkpoint = AtomicU64::new(230);
+  rewind(&data_dir, "block_metrics", 100, &checkpoint, 150).unwrap();
+  assert_eq!(checkpoint.load(std::sync::atomic::Ordering::SeqCst), 150);
+  // the cut of the rewind point is kept below it, later cuts are removed
+  assert!(data_dir.join(dataset(1).part_filename(100, 150)).exists());
+  assert!(!data_dir.join(dataset(1).filename()).exists());
+  assert!(!data_dir.join(dataset(2).part_filename(200, 220)).exists());
+  assert!(!data_dir.join(dataset(2).part_filename(220, 230)).exists());
+  let df = read_dataset(&data_dir, "block_metrics").unwrap().unwrap();
+  assert_eq!(df.column("height").unwrap().u64().unwrap().into_no_null_iter().collect::<Vec<_>>(), [0, 50, 99, 100]);
+  std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[test]
+fn test_validate_contracts() {
+  let usdc_weth = "0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc";
+  let contracts = IndexMap::from([
+    ("usdc_weth".to_string(), ContractStage::new(usdc_weth.to_string(), 10008355)),
+    ("lower".to_string(), ContractStage::new(usdc_weth.to_lowercase(), 10008355)),
+    ("typo".to_string(), ContractStage::new(usdc_weth.replace('B', "b"), 10008355)),
+    ("short".to_string(), ContractStage::new("0x1234".to_string(), 20000000)),
+  ]);
+  let mut errors = Vec::new();
+  validate_contracts("uniswap_pair_events", &contracts, Some(20000000), &mut errors);
+  assert_eq!(errors, [
+    format!("uniswap_pair_events.lower.contract: {} is registered as uniswap_pair_events.usdc_weth already", usdc_weth.to_lowercase()),
+    format!("uniswap_pair_events.typo.contract: bad checksum of {:?}, expected {usdc_weth:?}", usdc_weth.replace('B', "b")),
+    "uniswap_pair_events.short.contract: invalid address \"0x1234\": Invalid input length".to_string(),
+    "uniswap_pair_events.short.created: 20000000 is past the head 19999999".to_string(),
+  ]);
+}
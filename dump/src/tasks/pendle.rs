@@ -3,9 +3,10 @@ use std::sync::{atomic::AtomicU64, Arc};
 use ethers_providers::Middleware;
 use indexmap::IndexMap;
 
-use crate::{config::Config, metrics};
+use ethers_core::types::Address;
+use crate::{config::{glob_match, Config}, metrics, rpc::eth::LogsPageSize, Result};
 
-use super::{ContractStage, EventListener, RunConfig, RunEvent};
+use super::{batch_contracts, group_page_size, read_dataset, register_contract, validate_contracts, BatchRunConfig, ContractStage, EventListener, RunConfig, RunEvent, Task, TaskFuture};
 
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -14,6 +15,8 @@ pub struct PendleStage {
   pub pendle2_market_factory_events: Arc<AtomicU64>,
   #[serde(default)]
   pub pendle2_market_events: IndexMap<String, ContractStage>,
+  #[serde(skip)]
+  pub pendle2_market_factory_page_size: LogsPageSize,
 }
 
 impl Default for PendleStage {
@@ -21,28 +24,128 @@ impl Default for PendleStage {
     Self {
       pendle2_market_factory_events: Self::default_pendle2_market_factory_events(),
       pendle2_market_events: Default::default(),
+      pendle2_market_factory_page_size: Default::default(),
     }
   }
 }
 
 impl PendleStage {
+  const PENDLE2_MARKET_FACTORY_START: u64 = 18_000_000;
+
   fn default_pendle2_market_factory_events() -> Arc<AtomicU64> {
-    Arc::new(AtomicU64::new(18_000_000))
+    Arc::new(AtomicU64::new(Self::PENDLE2_MARKET_FACTORY_START))
   }
 
-  pub async fn run_tasks<P: Middleware>(&self, client: Arc<P>, config: &Config, default_event_listener: impl EventListener<RunEvent> + Copy) -> crate::Result<()> {
-    RunConfig::new(&config, self.pendle2_market_factory_events.clone(), "pendle2_market_factory_events", &|start, end|
-      metrics::pendle::fetch_pendle_market_factory(client.clone(), start, end)
-    ).run(default_event_listener).await?;
+  pub const FAMILY: &'static str = "pendle";
+
+  pub fn tasks(&self) -> Vec<Task> {
+    let mut result = vec![
+      Task::new(Self::FAMILY, "pendle2_market_factory_events".to_string(), self.pendle2_market_factory_events.clone()).with_start(Self::PENDLE2_MARKET_FACTORY_START),
+    ];
+    result.extend(self.pendle2_market_events.iter().map(|(name, market)| market.task(Self::FAMILY, format!("pendle2_market_events_{}", name))));
+    result
+  }
+
+  pub fn init_checkpoints(&self, cut: u64) {
+    self.pendle2_market_events.values().for_each(|market| market.init_checkpoint(cut));
+  }
+
+  pub fn validate(&self, head: Option<u64>, errors: &mut Vec<String>) {
+    validate_contracts("pendle2_market_events", &self.pendle2_market_events, head, errors);
+  }
+
+  /// Register markets from `pendle2_market_factory_events` whose underlying token or PT name is allowlisted,
+  /// and fill in expiry of known markets. Returns how many markets are new.
+  pub fn register_markets(&mut self, config: &Config) -> Result<usize> {
+    let registry = &config.registry.pendle2;
+    // an empty allowlist registers nothing, markets added by hand still get their expiry
+    let Some(df) = read_dataset(&config.data_dir, "pendle2_market_factory_events")? else {
+      return Ok(0);
+    };
+    let (height, market, pt_name, st_address, expiry) = (df.column("height")?.u64()?, df.column("market_address")?.str()?, df.column("pt_name")?.str()?, df.column("st_address")?.str()?, df.column("expiry")?.u64()?);
+    let mut count = 0;
+    for i in 0..df.height() {
+      let (Some(height), Some(market)) = (height.get(i), market.get(i)) else {
+        continue;
+      };
+      // markets whose info failed to fetch have no name, and are left to register by hand
+      if let Some(pt_name) = pt_name.get(i) {
+        let underlying = st_address.get(i).map(|i| i.parse::<Address>()).transpose()?.and_then(|i| config.registry.symbol(&i));
+        let selected = underlying.is_some_and(|symbol| registry.underlying.iter().any(|i| i == symbol))
+          || registry.pt_names.iter().any(|pattern| glob_match(pattern, pt_name));
+        if selected && register_contract(&mut self.pendle2_market_events, config.cut, market_name(pt_name), market, height) {
+          count += 1;
+        }
+      }
+      if let Some(stage) = self.pendle2_market_events.values_mut().find(|i| i.contract.eq_ignore_ascii_case(market)) {
+        stage.expiry = stage.expiry.or(expiry.get(i));
+      }
+    }
+    Ok(count)
+  }
 
+  /// Mark markets finished once they are fetched up to `block_length`, and the block is after expiry.
+  pub fn finish_expired(&self, block_length: u64, timestamp: u64) {
     for (name, market) in &self.pendle2_market_events {
-      market.init_checkpoint(config.cut);
-      let contract = market.contract.parse().unwrap();
-      RunConfig::new(&config, market.checkpoint.clone(), &format!("pendle2_market_events_{}", name), &|start, end|
-        metrics::pendle::fetch_pendle_market(client.clone(), start, end, contract)
-      ).run(default_event_listener).await?;
+      let expired = market.expiry.is_some_and(|expiry| expiry < timestamp);
+      if expired && market.checkpoint() >= block_length && !market.is_finished() {
+        info!(name, market.expiry, "market expired");
+        market.finished.store(true, std::sync::atomic::Ordering::SeqCst);
+      }
     }
+  }
 
-    Ok(())
+  pub fn run_tasks<'a, P: Middleware + 'static>(&'a self, client: Arc<P>, config: &'a Config, default_event_listener: impl EventListener<RunEvent> + Copy + 'a) -> Vec<TaskFuture<'a>> {
+    let mut result = Vec::<TaskFuture>::new();
+    if config.tasks.is_selected(Self::FAMILY, "pendle2_market_factory_events") {
+      self.pendle2_market_factory_page_size.init(config.page_size.factory);
+      let client = client.clone();
+      result.push(Box::pin(async move {
+        RunConfig::new(config, self.pendle2_market_factory_events.clone(), "pendle2_market_factory_events", &|start, end|
+          metrics::pendle::fetch_pendle_market_factory(client.clone(), start, end, &self.pendle2_market_factory_page_size, config.log_concurrency)
+        ).run(default_event_listener).await
+      }));
+    }
+
+    for markets in batch_contracts(&self.pendle2_market_events, config, Self::FAMILY, "pendle2_market_events") {
+      let client = client.clone();
+      let page_size = group_page_size(&markets, config.page_size.pair);
+      result.push(Box::pin(async move {
+        let contracts = markets.iter().map(|(_, i)| i.contract.parse()).collect::<Result<Vec<_>, _>>()?;
+        BatchRunConfig::new(config, markets, &|start, end|
+          metrics::pendle::fetch_pendle_markets(client.clone(), start, end, &contracts, &page_size, config.log_concurrency)
+        ).run(default_event_listener).await
+      }));
+    }
+    result
   }
 }
+
+/// `PT-stETH-26DEC2024` to `steth_26dec2024`, so it could be a bare key in toml
+fn market_name(pt_name: &str) -> String {
+  let name = pt_name.strip_prefix("PT-").unwrap_or(pt_name);
+  name.to_lowercase().chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+#[test]
+fn test_register_markets_expiry() {
+  use polars::{df, prelude::ParquetWriter};
+  let data_dir = std::env::temp_dir().join(format!("dump_test_register_markets_{}", std::process::id()));
+  std::fs::create_dir_all(&data_dir).unwrap();
+  let market = "0x7d372819240D14fB477f17b964f95F33BeB4c704";
+  let mut df = df!(
+    "height" => [18969500u64],
+    "market_address" => [market.to_lowercase()],
+    "pt_name" => ["PT-weETH-27JUN2024"],
+    "st_address" => [None::<&str>],
+    "expiry" => [1719446400u64],
+  ).unwrap();
+  ParquetWriter::new(std::fs::File::create(data_dir.join("pendle2_market_factory_events_1000000.18.parquet")).unwrap()).finish(&mut df).unwrap();
+  let config = Config { data_dir: data_dir.clone(), ..Default::default() };
+  let mut stage = PendleStage::default();
+  // added by hand, while the allowlist is empty
+  stage.pendle2_market_events.insert("weeth_27jun2024".to_string(), ContractStage::new(market.to_string(), 18969500));
+  assert_eq!(stage.register_markets(&config).unwrap(), 0);
+  assert_eq!(stage.pendle2_market_events["weeth_27jun2024"].expiry, Some(1719446400));
+  std::fs::remove_dir_all(&data_dir).unwrap();
+}
@@ -2,10 +2,11 @@ use std::sync::{atomic::AtomicU64, Arc};
 
 use ethers_providers::Middleware;
 use indexmap::IndexMap;
+use polars::lazy::{dsl::{col, lit}, frame::IntoLazy as _};
 
-use crate::{config::Config, metrics, tasks::{EventListener, RunEvent}};
+use crate::{config::Config, metrics::{self, ToChecksumHex as _}, rpc::eth::LogsPageSize, tasks::{batch_contracts, group_page_size, read_dataset, register_contract, validate_contracts, BatchRunConfig, EventListener, RunEvent}, Result};
 
-use super::{ContractStage, RunConfig};
+use super::{ContractStage, RunConfig, Task, TaskFuture};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct UniswapStage {
@@ -17,6 +18,10 @@ pub struct UniswapStage {
   pub uniswap_pair_events: IndexMap<String, ContractStage>,
   #[serde(default)]
   pub uniswap3_pair_events: IndexMap<String, ContractStage>,
+  #[serde(skip)]
+  pub uniswap_factory_page_size: LogsPageSize,
+  #[serde(skip)]
+  pub uniswap3_factory_page_size: LogsPageSize,
 }
 
 impl Default for UniswapStage {
@@ -26,42 +31,143 @@ impl Default for UniswapStage {
       uniswap3_factory_events: Self::default_uniswap3_factory_events(),
       uniswap_pair_events: Default::default(),
       uniswap3_pair_events: Default::default(),
+      uniswap_factory_page_size: Default::default(),
+      uniswap3_factory_page_size: Default::default(),
     }
   }
 }
 
 impl UniswapStage {
+  const UNISWAP_FACTORY_START: u64 = 9_000_000;
+  const UNISWAP3_FACTORY_START: u64 = 11_000_000;
+
   fn default_uniswap_factory_events() -> Arc<AtomicU64> {
-    Arc::new(AtomicU64::new(9_000_000))
+    Arc::new(AtomicU64::new(Self::UNISWAP_FACTORY_START))
   }
   fn default_uniswap3_factory_events() -> Arc<AtomicU64> {
-    Arc::new(AtomicU64::new(11_000_000))
+    Arc::new(AtomicU64::new(Self::UNISWAP3_FACTORY_START))
+  }
+
+  pub const FAMILY: &'static str = "uniswap";
+
+  pub fn tasks(&self) -> Vec<Task> {
+    let mut result = vec![
+      Task::new(Self::FAMILY, "uniswap_factory_events".to_string(), self.uniswap_factory_events.clone()).with_start(Self::UNISWAP_FACTORY_START),
+      Task::new(Self::FAMILY, "uniswap3_factory_events".to_string(), self.uniswap3_factory_events.clone()).with_start(Self::UNISWAP3_FACTORY_START),
+    ];
+    result.extend(self.uniswap_pair_events.iter().map(|(name, pair)| pair.task(Self::FAMILY, format!("uniswap_pair_events_{}", name))));
+    result.extend(self.uniswap3_pair_events.iter().map(|(name, pair)| pair.task(Self::FAMILY, format!("uniswap3_pair_events_{}", name))));
+    result
+  }
+
+  pub fn init_checkpoints(&self, cut: u64) {
+    self.uniswap_pair_events.values().chain(self.uniswap3_pair_events.values()).for_each(|pair| pair.init_checkpoint(cut));
+  }
+
+  pub fn validate(&self, head: Option<u64>, errors: &mut Vec<String>) {
+    validate_contracts("uniswap_pair_events", &self.uniswap_pair_events, head, errors);
+    validate_contracts("uniswap3_pair_events", &self.uniswap3_pair_events, head, errors);
+  }
+
+  /// Register pools between allowlisted tokens from `uniswap_factory_events` and `uniswap3_factory_events`,
+  /// returns how many pools are new.
+  pub fn register_pairs(&mut self, config: &Config) -> Result<usize> {
+    if config.registry.tokens.is_empty() {
+      return Ok(0);
+    }
+    Ok(self.register_uniswap2_pairs(config)? + self.register_uniswap3_pairs(config)?)
   }
 
-  pub async fn run_tasks<P: Middleware>(&self, client: Arc<P>, config: &Config, default_event_listener: impl EventListener<RunEvent> + Copy) -> crate::Result<()> {
-    RunConfig::new(&config, self.uniswap_factory_events.clone(), "uniswap_factory_events", &|start, end|
-      metrics::uniswap_v2::fetch_uniswap_factory(client.clone(), start, end)
-    ).run(default_event_listener).await?;
-
-    RunConfig::new(&config, self.uniswap3_factory_events.clone(), "uniswap3_factory_events", &|start, end|
-      metrics::uniswap_v3::fetch_factory(client.clone(), start, end)
-    ).run(default_event_listener).await?;
-
-    for (name, pair) in &self.uniswap_pair_events {
-      pair.init_checkpoint(config.cut);
-      let contract = pair.contract.parse().unwrap();
-      RunConfig::new(&config, pair.checkpoint.clone(), &format!("uniswap_pair_events_{}", name), &|start, end|
-        metrics::uniswap_v2::fetch_uniswap_pair(client.clone(), start, end, contract)
-      ).run(default_event_listener).await?;
+  fn register_uniswap2_pairs(&mut self, config: &Config) -> Result<usize> {
+    let Some(df) = read_dataset(&config.data_dir, "uniswap_factory_events")? else {
+      return Ok(0);
+    };
+    let factory = metrics::uniswap_v2::consts::CONTRACT_UniswapV2Factory.to_checksum_hex();
+    let df = df.lazy().filter(col("contract").eq(lit(factory))).collect()?;
+    let (height, token0, token1, pair) = (df.column("height")?.u64()?, df.column("token0")?.str()?, df.column("token1")?.str()?, df.column("pair")?.str()?);
+    let mut count = 0;
+    for i in 0..df.height() {
+      let (Some(height), Some(token0), Some(token1), Some(pair)) = (height.get(i), token0.get(i), token1.get(i), pair.get(i)) else {
+        continue;
+      };
+      let Some(name) = config.registry.pair_name(&token0.parse()?, &token1.parse()?) else {
+        continue;
+      };
+      if register_contract(&mut self.uniswap_pair_events, config.cut, name, pair, height) {
+        count += 1;
+      }
+    }
+    Ok(count)
+  }
+
+  fn register_uniswap3_pairs(&mut self, config: &Config) -> Result<usize> {
+    let Some(df) = read_dataset(&config.data_dir, "uniswap3_factory_events")? else {
+      return Ok(0);
+    };
+    let factory = metrics::uniswap_v3::consts::CONTRACT_UniswapV3Factory.to_checksum_hex();
+    let df = df.lazy().filter(col("contract").eq(lit(factory))).collect()?;
+    let (height, token0, token1, fee, pair) = (df.column("height")?.u64()?, df.column("token0")?.str()?, df.column("token1")?.str()?, df.column("fee")?.u32()?, df.column("pair")?.str()?);
+    let mut count = 0;
+    for i in 0..df.height() {
+      let (Some(height), Some(token0), Some(token1), Some(fee), Some(pair)) = (height.get(i), token0.get(i), token1.get(i), fee.get(i), pair.get(i)) else {
+        continue;
+      };
+      if !config.registry.uniswap3_fees.is_empty() && !config.registry.uniswap3_fees.contains(&fee) {
+        continue;
+      }
+      let Some(name) = config.registry.pair_name(&token0.parse()?, &token1.parse()?) else {
+        continue;
+      };
+      if register_contract(&mut self.uniswap3_pair_events, config.cut, format!("{}_{}", name, fee), pair, height) {
+        count += 1;
+      }
+    }
+    Ok(count)
+  }
+
+  pub fn run_tasks<'a, P: Middleware + 'a>(&'a self, client: Arc<P>, config: &'a Config, default_event_listener: impl EventListener<RunEvent> + Copy + 'a) -> Vec<TaskFuture<'a>> {
+    let mut result = Vec::<TaskFuture>::new();
+    if config.tasks.is_selected(Self::FAMILY, "uniswap_factory_events") {
+      self.uniswap_factory_page_size.init(config.page_size.factory);
+      let client = client.clone();
+      result.push(Box::pin(async move {
+        RunConfig::new(config, self.uniswap_factory_events.clone(), "uniswap_factory_events", &|start, end|
+          metrics::uniswap_v2::fetch_uniswap_factory(client.clone(), start, end, &self.uniswap_factory_page_size, config.log_concurrency)
+        ).run(default_event_listener).await
+      }));
+    }
+
+    if config.tasks.is_selected(Self::FAMILY, "uniswap3_factory_events") {
+      self.uniswap3_factory_page_size.init(config.page_size.factory);
+      let client = client.clone();
+      result.push(Box::pin(async move {
+        RunConfig::new(config, self.uniswap3_factory_events.clone(), "uniswap3_factory_events", &|start, end|
+          metrics::uniswap_v3::fetch_factory(client.clone(), start, end, &self.uniswap3_factory_page_size, config.log_concurrency)
+        ).run(default_event_listener).await
+      }));
+    }
+
+    for pairs in batch_contracts(&self.uniswap_pair_events, config, Self::FAMILY, "uniswap_pair_events") {
+      let client = client.clone();
+      let page_size = group_page_size(&pairs, config.page_size.pair);
+      result.push(Box::pin(async move {
+        let contracts = pairs.iter().map(|(_, i)| i.contract.parse()).collect::<Result<Vec<_>, _>>()?;
+        BatchRunConfig::new(config, pairs, &|start, end|
+          metrics::uniswap_v2::fetch_uniswap_pairs(client.clone(), start, end, &contracts, &page_size, config.log_concurrency)
+        ).run(default_event_listener).await
+      }));
     }
 
-    for (name, pair) in &self.uniswap3_pair_events {
-      pair.init_checkpoint(config.cut);
-      let contract = pair.contract.parse().unwrap();
-      RunConfig::new(&config, pair.checkpoint.clone(), &format!("uniswap3_pair_events_{}", name), &|start, end|
-        metrics::uniswap_v3::fetch_uniswap_pair(client.clone(), start, end, contract)
-      ).run(default_event_listener).await?;
+    for pairs in batch_contracts(&self.uniswap3_pair_events, config, Self::FAMILY, "uniswap3_pair_events") {
+      let client = client.clone();
+      let page_size = group_page_size(&pairs, config.page_size.pair);
+      result.push(Box::pin(async move {
+        let contracts = pairs.iter().map(|(_, i)| i.contract.parse()).collect::<Result<Vec<_>, _>>()?;
+        BatchRunConfig::new(config, pairs, &|start, end|
+          metrics::uniswap_v3::fetch_uniswap_pairs(client.clone(), start, end, &contracts, &page_size, config.log_concurrency)
+        ).run(default_event_listener).await
+      }));
     }
-    Ok(())
+    result
   }
 }
@@ -29,16 +29,18 @@ def try_int(s: str):
     return None
 def all_datasets(path = None):
   if path is None:
-    path = Path("data").rglob("*.parquet")
+    # parts `{name}_{cut}.{idx}.parquet.{start}-{end}.part` hold the incomplete cut, sorted by start
+    path = itertools.chain(Path("data").rglob("*.parquet"), Path("data").rglob("*.parquet.*.part"))
   files = pl.DataFrame({
-    'path': path
+    'path': list(path)
   }).with_columns([
     pl.col('path').map_elements(lambda x: x.name.split(".")[0], return_dtype=pl.String).alias('prefix'),
     pl.col('path').map_elements(lambda x: try_int(x.name.split(".")[1]), return_dtype=pl.Int64).alias('idx'),
+    pl.col('path').map_elements(lambda x: try_int((x.name.split(".") + [""] * 4)[3].split("-")[0]), return_dtype=pl.Int64).alias('part'),
     pl.col('path').map_elements(lambda x: str(x), return_dtype=pl.String).alias('path'),
   ]).with_columns([
     pl.col('prefix').map_elements(lambda x: try_int(x.split("_")[-1]), return_dtype=pl.Int64).alias('cut'),
-  ]).sort('prefix', 'idx')
+  ]).sort('prefix', 'idx', 'part', nulls_last=False)
   datasets = files.group_by('prefix').agg([
     pl.first('cut'),
     pl.max('idx').alias('max'),